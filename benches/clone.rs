@@ -0,0 +1,42 @@
+//! Cloning a base command, which shares its arguments and environment rather than
+//! copying them, against copying its arguments outright.
+//!
+//!     cargo bench --bench clone
+
+mod common;
+
+use command_limits::CommandBuilder;
+
+use common::bench;
+use std::ffi::OsString;
+use std::num::NonZeroUsize;
+
+fn base(args: usize) -> CommandBuilder {
+    let mut cmd = CommandBuilder::new_capture_env("echo").unwrap();
+    for i in 0..args {
+        cmd.arg(format!("--fixed-argument-{}", i)).unwrap();
+    }
+    cmd
+}
+
+fn main() {
+    for args in [2, 100] {
+        let base = base(args);
+
+        bench(&format!("clone, {} base arguments", args), || base.clone());
+        bench(&format!("copy argv, {} base arguments", args), || {
+            base.argv().map(OsString::from).collect::<Vec<_>>()
+        });
+    }
+
+    let mut base = base(100);
+    base.set_max_data_args(NonZeroUsize::new(10)).unwrap();
+    let items: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+
+    bench("batch 1000 items by 10, 100 base arguments", || {
+        base.batch(&items).count()
+    });
+    bench("pack 1000 items by 10, 100 base arguments", || {
+        command_limits::pack(base.clone(), &items).count()
+    });
+}
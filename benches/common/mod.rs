@@ -0,0 +1,56 @@
+//! Shared by the benchmarks: an allocator which counts allocations, and a simple
+//! timing loop, so they need nothing beyond std.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// How long to run each benchmark for.
+const DURATION: Duration = Duration::from_millis(500);
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Run `f` repeatedly, printing the mean time and number of allocations (including
+/// reallocations) of each run.
+pub fn bench<T, F: FnMut() -> T>(name: &str, mut f: F) {
+    black_box(f());
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut runs = 0;
+    while start.elapsed() < DURATION {
+        black_box(f());
+        runs += 1;
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "{:<48} {:>12?} {:>10.1} allocations",
+        name,
+        elapsed / runs,
+        allocations as f64 / f64::from(runs)
+    );
+}
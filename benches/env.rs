@@ -0,0 +1,60 @@
+//! Environment-heavy workloads: setting, replacing, looking up and removing many
+//! variables, and turning the result into a `Command`.
+//!
+//!     cargo bench --bench env
+
+mod common;
+
+use command_limits::CommandBuilder;
+
+use common::bench;
+
+fn main() {
+    for count in [10, 1000] {
+        let vars: Vec<(String, String)> = (0..count)
+            .map(|i| (format!("COMMAND_LIMITS_VAR_{}", i), i.to_string()))
+            .collect();
+
+        let mut base = CommandBuilder::new_empty_env("env").unwrap();
+        for (key, value) in &vars {
+            base.env(key, value).unwrap();
+        }
+
+        bench(&format!("set {} vars", count), || {
+            let mut cmd = CommandBuilder::new_empty_env("env").unwrap();
+            for (key, value) in &vars {
+                cmd.env(key, value).unwrap();
+            }
+            cmd
+        });
+        bench(&format!("replace {} vars", count), || {
+            let mut cmd = base.clone();
+            for (key, _) in &vars {
+                cmd.env(key, "replaced").unwrap();
+            }
+            cmd
+        });
+        bench(&format!("get {} vars", count), || {
+            vars.iter()
+                .filter(|(key, _)| base.get_env(key).is_some())
+                .count()
+        });
+        bench(&format!("remove {} vars", count), || {
+            let mut cmd = base.clone();
+            for (key, _) in &vars {
+                cmd.env_remove(key);
+            }
+            cmd
+        });
+        bench(&format!("get_envs with {} vars", count), || {
+            base.get_envs().count()
+        });
+        bench(&format!("into_command with {} vars", count), || {
+            base.into_command()
+        });
+    }
+
+    bench("capture the process environment", || {
+        CommandBuilder::new_capture_env("env").unwrap()
+    });
+}
@@ -0,0 +1,56 @@
+//! Filling a command with many small arguments or environment variables, with and
+//! without count limits which let the builder preallocate for them.
+//!
+//!     cargo bench --bench prealloc
+
+mod common;
+
+use command_limits::{CommandBuilder, CommandLimits};
+
+use common::bench;
+use std::num::NonZeroUsize;
+
+const COUNT: usize = 1000;
+
+fn limits(arg_count: Option<usize>, env_count: Option<usize>) -> CommandLimits {
+    CommandLimits {
+        arg_count: arg_count.and_then(NonZeroUsize::new),
+        env_count: env_count.and_then(NonZeroUsize::new),
+        ..CommandLimits::default()
+    }
+}
+
+fn main() {
+    let args: Vec<String> = (0..COUNT).map(|i| i.to_string()).collect();
+    let vars: Vec<(String, String)> = (0..COUNT / 4)
+        .map(|i| (format!("VAR_{}", i), i.to_string()))
+        .collect();
+
+    for (name, arg_count) in [
+        ("without arg_count", None),
+        ("with arg_count", Some(COUNT + 1)),
+    ] {
+        let limits = limits(arg_count, None);
+        bench(&format!("{} args, {}", COUNT, name), || {
+            let mut cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+            for arg in &args {
+                cmd.arg(arg).unwrap();
+            }
+            cmd
+        });
+    }
+
+    for (name, env_count) in [
+        ("without env_count", None),
+        ("with env_count", Some(vars.len())),
+    ] {
+        let limits = limits(None, env_count);
+        bench(&format!("{} vars, {}", vars.len(), name), || {
+            let mut cmd = CommandBuilder::empty_env_with_limits("env", limits).unwrap();
+            for (key, value) in &vars {
+                cmd.env(key, value).unwrap();
+            }
+            cmd
+        });
+    }
+}
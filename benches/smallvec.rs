@@ -0,0 +1,40 @@
+//! Building short commands, to compare allocations with and without argument lists
+//! stored inline:
+//!
+//!     cargo bench --bench smallvec
+//!     cargo bench --bench smallvec --features smallvec
+
+mod common;
+
+use command_limits::CommandBuilder;
+
+use common::bench;
+
+fn main() {
+    let feature = if cfg!(feature = "smallvec") {
+        "smallvec"
+    } else {
+        "vec"
+    };
+
+    bench(&format!("3 arguments ({})", feature), || {
+        let mut cmd = CommandBuilder::new_empty_env("ls").unwrap();
+        cmd.arg("-l")
+            .unwrap()
+            .arg("-a")
+            .unwrap()
+            .arg("/tmp")
+            .unwrap();
+        cmd
+    });
+    bench(&format!("3 arguments to Command ({})", feature), || {
+        let mut cmd = CommandBuilder::new_empty_env("ls").unwrap();
+        cmd.arg("-l")
+            .unwrap()
+            .arg("-a")
+            .unwrap()
+            .arg("/tmp")
+            .unwrap();
+        cmd.into_command()
+    });
+}
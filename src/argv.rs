@@ -0,0 +1,204 @@
+use std::ffi::OsString;
+use std::iter::Chain;
+use std::ops::{Index, Range};
+use std::slice;
+use std::sync::Arc;
+
+use crate::Argv;
+
+pub(crate) type ArgvRange<'a> = Chain<slice::Iter<'a, OsString>, slice::Iter<'a, OsString>>;
+
+/// The program and arguments of a command.
+///
+/// Arguments are added to a list shared between clones for as long as it isn't
+/// shared, and to a list owned by each clone once it is.  Cloning a base command
+/// for each batch therefore only bumps a reference count, and each batch owns just
+/// the arguments added to it.
+#[derive(Debug, Default)]
+pub(crate) struct SharedArgv {
+    shared: Arc<Argv>,
+    owned: Argv,
+}
+
+impl Clone for SharedArgv {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            owned: self.owned.clone(),
+        }
+    }
+
+    fn clone_from(&mut self, source: &Self) {
+        self.shared.clone_from(&source.shared);
+        self.owned.clone_from(&source.owned);
+    }
+}
+
+impl SharedArgv {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            shared: Arc::new(Argv::with_capacity(capacity)),
+            owned: Argv::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shared.len() + self.owned.len()
+    }
+
+    pub(crate) fn first(&self) -> Option<&OsString> {
+        self.shared.first().or_else(|| self.owned.first())
+    }
+
+    pub(crate) fn last(&self) -> Option<&OsString> {
+        self.owned.last().or_else(|| self.shared.last())
+    }
+
+    // Only copies the shared list if the last argument is in it.
+    pub(crate) fn last_mut(&mut self) -> Option<&mut OsString> {
+        if self.owned.is_empty() {
+            Arc::make_mut(&mut self.shared).last_mut()
+        } else {
+            self.owned.last_mut()
+        }
+    }
+
+    // The list new arguments go to: the shared one, if no clone is using it.
+    fn tail(&mut self) -> &mut Argv {
+        match Arc::get_mut(&mut self.shared) {
+            Some(shared) if self.owned.is_empty() => shared,
+            _ => &mut self.owned,
+        }
+    }
+
+    pub(crate) fn push(&mut self, arg: OsString) {
+        self.tail().push(arg);
+    }
+
+    pub(crate) fn extend<I: IntoIterator<Item = OsString>>(&mut self, args: I) {
+        self.tail().extend(args);
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        let shared_len = self.shared.len();
+        if shared_len <= len {
+            self.owned.truncate(len - shared_len);
+            return;
+        }
+
+        self.owned.clear();
+        match Arc::get_mut(&mut self.shared) {
+            Some(shared) => shared.truncate(len),
+            None => self.shared = Arc::new(Argv::from(&self.shared[..len])),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> ArgvRange<'_> {
+        self.shared.iter().chain(self.owned.iter())
+    }
+
+    /// Iterate over the arguments within `range`.
+    ///
+    /// # Panics
+    ///
+    /// If `range` is out of bounds.
+    pub(crate) fn range(&self, range: Range<usize>) -> ArgvRange<'_> {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "range {:?} out of bounds for {} arguments",
+            range,
+            self.len()
+        );
+
+        let shared_len = self.shared.len();
+        let shared = &self.shared[range.start.min(shared_len)..range.end.min(shared_len)];
+        let owned = &self.owned
+            [range.start.saturating_sub(shared_len)..range.end.saturating_sub(shared_len)];
+        shared.iter().chain(owned.iter())
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<OsString> {
+        let mut argv: Vec<OsString> = Arc::unwrap_or_clone(self.shared).into_iter().collect();
+        argv.extend(self.owned);
+        argv
+    }
+
+    #[cfg(all(test, feature = "smallvec"))]
+    pub(crate) fn spilled(&self) -> bool {
+        self.shared.spilled() || self.owned.spilled()
+    }
+}
+
+impl Index<usize> for SharedArgv {
+    type Output = OsString;
+
+    fn index(&self, index: usize) -> &OsString {
+        match index.checked_sub(self.shared.len()) {
+            Some(index) => &self.owned[index],
+            None => &self.shared[index],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(args: &[&str]) -> SharedArgv {
+        let mut argv = SharedArgv::default();
+        argv.extend(args.iter().map(OsString::from));
+        argv
+    }
+
+    #[test]
+    fn clones_share_existing_arguments() {
+        let base = argv(&["echo", "a"]);
+        let mut batch = base.clone();
+        batch.push("b".into());
+
+        assert!(Arc::ptr_eq(&base.shared, &batch.shared));
+        assert!(batch.iter().eq(["echo", "a", "b"]));
+        assert!(base.iter().eq(["echo", "a"]));
+        assert_eq!(batch[2], "b");
+    }
+
+    #[test]
+    fn ranges_span_shared_and_owned() {
+        let base = argv(&["echo", "a", "b"]);
+        let mut batch = base.clone();
+        batch.extend(["c", "d"].map(OsString::from));
+
+        assert!(batch.range(0..5).eq(["echo", "a", "b", "c", "d"]));
+        assert!(batch.range(2..4).eq(["b", "c"]));
+        assert!(batch.range(3..5).eq(["c", "d"]));
+        assert!(batch.range(1..2).eq(["a"]));
+        assert_eq!(batch.range(3..3).count(), 0);
+    }
+
+    #[test]
+    fn truncating_shared_arguments_leaves_clones() {
+        let base = argv(&["echo", "a", "b"]);
+        let mut batch = base.clone();
+        batch.push("c".into());
+
+        batch.truncate(3);
+        assert!(batch.iter().eq(["echo", "a", "b"]));
+        batch.truncate(1);
+        assert!(batch.iter().eq(["echo"]));
+        assert!(base.iter().eq(["echo", "a", "b"]));
+
+        batch.push("x".into());
+        *batch.last_mut().unwrap() = "y".into();
+        assert!(batch.into_vec().iter().eq(["echo", "y"]));
+    }
+
+    #[test]
+    fn modifying_shared_last_argument_copies() {
+        let base = argv(&["echo", "a"]);
+        let mut batch = base.clone();
+        batch.last_mut().unwrap().push("b");
+
+        assert!(batch.iter().eq(["echo", "ab"]));
+        assert!(base.iter().eq(["echo", "a"]));
+    }
+}
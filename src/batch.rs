@@ -0,0 +1,388 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::num::NonZeroUsize;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crate::{CommandBuilder, Result};
+
+// A callback given each filled builder by `CommandBatches::on_batch`.
+type BatchHook<'a> = Box<dyn FnMut(&mut CommandBuilder) + 'a>;
+
+/// An iterator of `Command`s, each packed with as many arguments as will fit.
+///
+/// Created by `CommandBuilder::batch`.
+pub struct CommandBatches<'a, I, S> {
+    base: &'a CommandBuilder,
+    cmd: CommandBuilder,
+    filler: Filler<I, S>,
+    on_batch: Option<BatchHook<'a>>,
+}
+
+impl<I: fmt::Debug, S: fmt::Debug> fmt::Debug for CommandBatches<'_, I, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CommandBatches")
+            .field("base", &self.base)
+            .field("filler", &self.filler)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, I, S> CommandBatches<'a, I, S>
+where
+    I: Iterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    pub(crate) fn new(base: &'a CommandBuilder, args: I) -> Self {
+        Self {
+            base,
+            cmd: base.clone(),
+            filler: Filler::new(args),
+            on_batch: None,
+        }
+    }
+
+    /// Return the total `arg_size` of the commands yielded so far, including the
+    /// base command's own arguments in each.
+    ///
+    /// Once iteration is complete this is the space taken by all the commands.
+    pub fn total_bytes_processed(&self) -> usize {
+        self.filler.total_bytes
+    }
+
+    /// Call `f` with each builder once it's filled, before it's turned into a
+    /// `Command`, such as to log it or set its `current_dir`.  This generalises
+    /// `xargs -t`.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let base = CommandBuilder::new("echo")?;
+    /// let mut lines = vec![];
+    /// let batches: Vec<_> = base
+    ///     .batch(["a", "b", "c"])
+    ///     .on_batch(|cmd| lines.push(cmd.to_command_line()))
+    ///     .collect::<Result<_, _>>()?;
+    /// assert_eq!(lines.len(), batches.len());
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn on_batch<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&mut CommandBuilder) + 'a,
+    {
+        self.on_batch = Some(Box::new(f));
+        self
+    }
+}
+
+impl<I, S> Iterator for CommandBatches<'_, I, S>
+where
+    I: Iterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    type Item = Result<Command>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.cmd.reset_to(self.base);
+        self.filler.fill(&mut self.cmd).map(|res| {
+            res.and_then(|_| {
+                if let Some(on_batch) = &mut self.on_batch {
+                    on_batch(&mut self.cmd);
+                }
+                self.cmd.try_into_command()
+            })
+        })
+    }
+}
+
+/// Pack `items` into copies of `base`, each filled with as many as will fit.
+///
+/// Unlike `CommandBuilder::batch` this yields the builders themselves, so they can
+/// be customised further before being turned into commands.  Items are pulled
+/// lazily, only as far as one beyond those in the current builder.  An item which
+/// cannot fit even in an otherwise empty builder ends iteration with an error.
+pub fn pack<I, S>(base: CommandBuilder, items: I) -> Packer<I::IntoIter, S>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    Packer {
+        base,
+        filler: Filler::new(items.into_iter()),
+    }
+}
+
+/// An iterator of `CommandBuilder`s, each packed with as many arguments as will
+/// fit.
+///
+/// Created by `pack`.
+#[derive(Debug)]
+pub struct Packer<I, S> {
+    base: CommandBuilder,
+    filler: Filler<I, S>,
+}
+
+impl<I, S> Packer<I, S> {
+    /// Return the total `arg_size` of the builders yielded so far, as with
+    /// `CommandBatches::total_bytes_processed`.
+    pub fn total_bytes_processed(&self) -> usize {
+        self.filler.total_bytes
+    }
+}
+
+impl<I, S> Iterator for Packer<I, S>
+where
+    I: Iterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    type Item = Result<CommandBuilder>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The arguments of the base are shared with the clone, not copied
+        let mut cmd = self.base.clone();
+        self.filler.fill(&mut cmd).map(|res| res.map(|_| cmd))
+    }
+}
+
+/// Run each command from `batches`, with up to `jobs` running at once, as with
+/// `xargs -P`.
+///
+/// The returned iterator yields the exit status of each command in the order they
+/// finish, waiting for them as required, and starts another whenever one does.  An
+/// error from `batches`, such as `Error::TooLarge`, or from spawning a command ends
+/// iteration after the statuses of those already running.
+///
+/// Each command is waited for by a thread of its own, so commands still running
+/// when the iterator is dropped are reaped as they finish.
+pub fn run_parallel<I>(batches: I, jobs: NonZeroUsize) -> RunParallel<I::IntoIter>
+where
+    I: IntoIterator<Item = Result<Command>>,
+{
+    let (finished, statuses) = mpsc::channel();
+    RunParallel {
+        batches: batches.into_iter(),
+        jobs,
+        running: 0,
+        finished,
+        statuses,
+        error: None,
+        done: false,
+    }
+}
+
+/// An iterator of exit statuses from commands run in parallel.
+///
+/// Created by `run_parallel`.
+#[derive(Debug)]
+pub struct RunParallel<I> {
+    batches: I,
+    jobs: NonZeroUsize,
+    running: usize,
+    finished: Sender<io::Result<ExitStatus>>,
+    statuses: Receiver<io::Result<ExitStatus>>,
+    error: Option<io::Error>,
+    done: bool,
+}
+
+impl<I> RunParallel<I> {
+    fn wait_for(&mut self, mut child: Child) {
+        let finished = self.finished.clone();
+        thread::spawn(move || {
+            // The iterator may have been dropped, but the child is still reaped
+            let _ = finished.send(child.wait());
+        });
+        self.running += 1;
+    }
+}
+
+impl<I> Iterator for RunParallel<I>
+where
+    I: Iterator<Item = Result<Command>>,
+{
+    type Item = io::Result<ExitStatus>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done && self.running < self.jobs.get() {
+            match self.batches.next().map(|res| {
+                res.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+                    .and_then(|mut cmd| cmd.spawn())
+            }) {
+                Some(Ok(child)) => self.wait_for(child),
+                Some(Err(e)) => {
+                    self.error = Some(e);
+                    self.done = true;
+                }
+                None => self.done = true,
+            }
+        }
+
+        if self.running == 0 {
+            return self.error.take().map(Err);
+        }
+
+        // We hold a sender ourselves, so this only returns once a child finishes
+        self.running -= 1;
+        self.statuses.recv().ok()
+    }
+}
+
+/// The state shared by batching iterators: the remaining arguments, one which
+/// didn't fit in the previous batch, and the size of the batches filled so far.
+#[derive(Debug)]
+pub(crate) struct Filler<I, S> {
+    args: I,
+    pending: Option<S>,
+    total_bytes: usize,
+    done: bool,
+}
+
+impl<I, S> Filler<I, S>
+where
+    I: Iterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    pub(crate) fn new(args: I) -> Self {
+        Self {
+            args,
+            pending: None,
+            total_bytes: 0,
+            done: false,
+        }
+    }
+
+    /// Fill `cmd`, a fresh copy of the base command, with as many arguments as will
+    /// fit, returning `None` once there are no more.
+    ///
+    /// An argument which cannot fit even in an otherwise empty command ends
+    /// iteration with an error, after yielding any arguments before it.
+    pub(crate) fn fill(&mut self, cmd: &mut CommandBuilder) -> Option<Result<()>> {
+        let res = self.fill_next(cmd);
+        if let Some(Ok(())) = res {
+            self.total_bytes += cmd.arg_size();
+        }
+        res
+    }
+
+    fn fill_next(&mut self, cmd: &mut CommandBuilder) -> Option<Result<()>> {
+        if self.done {
+            return None;
+        }
+
+        let mut added = 0;
+
+        while let Some(arg) = self.pending.take().or_else(|| self.args.next()) {
+            match cmd.arg(&arg) {
+                Ok(_) => added += 1,
+                Err(_) if added > 0 => {
+                    self.pending = Some(arg);
+                    return Some(Ok(()));
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        self.done = true;
+        (added > 0).then_some(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sizing::arg_len;
+
+    #[test]
+    fn total_bytes_processed() {
+        let base = CommandBuilder::new_empty_env("echo").unwrap();
+        let items = ["a", "bb", "ccc"];
+        let expected = 3 * arg_len("echo") + items.iter().map(arg_len).sum::<usize>();
+
+        let mut one = base.clone();
+        one.set_max_data_args(NonZeroUsize::new(1)).unwrap();
+        let mut batches = one.batch(items);
+        assert_eq!(batches.by_ref().count(), 3);
+        assert_eq!(batches.total_bytes_processed(), expected);
+
+        let mut packer = pack(base, items);
+        assert_eq!(packer.by_ref().count(), 1);
+        assert_eq!(
+            packer.total_bytes_processed(),
+            expected - 2 * arg_len("echo")
+        );
+    }
+
+    #[test]
+    fn batches_split_at_max_data_args_with_placeholder() {
+        let mut base = CommandBuilder::new_empty_env("find").unwrap();
+        base.args(&[".", "-exec", "ls", "{}", "+"])
+            .unwrap()
+            .placeholder("{}");
+        base.set_max_data_args(NonZeroUsize::new(2)).unwrap();
+
+        let packed: Vec<_> = pack(base.clone(), ["a", "b", "c", "d", "e"])
+            .map(|cmd| {
+                cmd.unwrap()
+                    .get_args()
+                    .map(|arg| arg.to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(
+            packed,
+            [
+                vec![".", "-exec", "ls", "a", "b", "+"],
+                vec![".", "-exec", "ls", "c", "d", "+"],
+                vec![".", "-exec", "ls", "e", "+"],
+            ]
+        );
+
+        let batches: Vec<_> = base
+            .batch(["a", "b", "c", "d", "e"])
+            .map(|cmd| {
+                cmd.unwrap()
+                    .get_args()
+                    .map(|arg| arg.to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(batches, packed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_parallel_yields_statuses_as_commands_finish() {
+        let exit = |script: &str| {
+            let mut cmd = CommandBuilder::new("sh").unwrap();
+            cmd.args(&["-c", script]).unwrap();
+            cmd.try_into_command()
+        };
+        let batches = vec![exit("sleep 1; exit 1"), exit("exit 2"), exit("exit 3")];
+
+        let codes: Vec<_> = run_parallel(batches, NonZeroUsize::new(2).unwrap())
+            .map(|status| status.unwrap().code())
+            .collect();
+        assert_eq!(codes, [Some(2), Some(3), Some(1)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_parallel_ends_with_batch_error() {
+        let batches = vec![
+            CommandBuilder::new("true").unwrap().try_into_command(),
+            Err(crate::Error::TooLarge),
+            CommandBuilder::new("true").unwrap().try_into_command(),
+        ];
+
+        let mut statuses = run_parallel(batches, NonZeroUsize::new(2).unwrap());
+        assert!(statuses.next().unwrap().unwrap().success());
+        assert_eq!(
+            statuses.next().unwrap().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(statuses.next().is_none());
+    }
+}
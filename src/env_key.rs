@@ -0,0 +1,133 @@
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+use crate::imp::{cmp_env_keys, hash_env_key};
+
+/// The name of an environment variable, compared as the platform compares them:
+/// exactly on Unix, and ignoring case on Windows.  The name is kept as given, for
+/// passing on to the command.
+#[derive(Clone, Debug)]
+pub(crate) struct EnvKey(OsString);
+
+impl EnvKey {
+    pub(crate) fn into_os_string(self) -> OsString {
+        self.0
+    }
+}
+
+impl PartialEq for EnvKey {
+    fn eq(&self, other: &Self) -> bool {
+        cmp_env_keys(&self.0, &other.0) == Ordering::Equal
+    }
+}
+
+impl Eq for EnvKey {}
+
+impl Hash for EnvKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_env_key(&self.0, state);
+    }
+}
+
+impl PartialOrd for EnvKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for EnvKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        cmp_env_keys(&self.0, &other.0)
+    }
+}
+
+impl Deref for EnvKey {
+    type Target = OsStr;
+
+    fn deref(&self) -> &OsStr {
+        &self.0
+    }
+}
+
+impl AsRef<OsStr> for EnvKey {
+    fn as_ref(&self) -> &OsStr {
+        &self.0
+    }
+}
+
+impl From<OsString> for EnvKey {
+    fn from(key: OsString) -> Self {
+        Self(key)
+    }
+}
+
+impl From<&OsStr> for EnvKey {
+    fn from(key: &OsStr) -> Self {
+        Self(key.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashMap;
+
+    fn key(key: &str) -> EnvKey {
+        EnvKey::from(OsStr::new(key))
+    }
+
+    fn hash(key: &EnvKey) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn keys_ignore_case() {
+        for (a, b) in [("Path", "PATH"), ("path", "PaTh"), ("é", "É"), ("", "")] {
+            assert_eq!(key(a), key(b));
+            assert_eq!(key(a).cmp(&key(b)), Ordering::Equal);
+            assert_eq!(hash(&key(a)), hash(&key(b)));
+        }
+
+        // Only characters with a single uppercase form are folded
+        assert_ne!(key("ß"), key("SS"));
+        assert_ne!(key("PATH"), key("PATHS"));
+        assert!(key("a") < key("B"));
+
+        let mut env = HashMap::new();
+        env.insert(key("Path"), 1);
+        assert_eq!(env.get(&key("PATH")), Some(&1));
+        assert_eq!(key("Path").into_os_string(), "Path");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn unpaired_surrogates_are_kept() {
+        use std::os::windows::ffi::OsStringExt;
+
+        let surrogate = |units: &[u16]| EnvKey::from(OsString::from_wide(units));
+        assert_eq!(
+            surrogate(&[0xD800, b'a' as u16]),
+            surrogate(&[0xD800, b'A' as u16])
+        );
+        assert_ne!(surrogate(&[0xD800]), surrogate(&[0xDC00]));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn keys_are_case_sensitive() {
+        assert_ne!(key("Path"), key("PATH"));
+        assert!(key("PATH") < key("Path"));
+        assert_eq!(hash(&key("PATH")), hash(&key("PATH")));
+
+        let mut env = HashMap::new();
+        env.insert(key("Path"), 1);
+        assert_eq!(env.get(&key("PATH")), None);
+        assert_eq!(env.get(&key("Path")), Some(&1));
+    }
+}
@@ -1,31 +1,102 @@
 use std::fmt;
 
+/// The part of a command a limit applies to.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Domain {
+    /// The program and its arguments.
+    Arg,
+    /// The environment variables.
+    Env,
+}
+
+impl fmt::Display for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Domain::Arg => "arguments",
+            Domain::Env => "environment",
+        })
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum Error {
     /// The value(s) given are too long to fit in the remaining argument/environment
     /// space, either because they are too big or there are already too many arguments.
     ///
     /// It is possible they could fit into another, smaller invocation.
-    InsufficientSpace,
+    InsufficientSpace {
+        /// The total space the command would need, including the value(s).
+        needed: usize,
+        /// The total space usable under the limit.
+        available: usize,
+        /// The part of the command responsible.  Where arguments and environment
+        /// share a limit, this is whichever takes up more of it.
+        domain: Domain,
+    },
     /// While there may be space for this value, the limit on the total number of
     /// arguments or environment variables would be exceeded.
-    TooMany,
+    TooMany {
+        /// The limit on the number of values.
+        limit: usize,
+        /// Whether the limit is on arguments or environment variables.
+        domain: Domain,
+    },
     /// The value(s) given exceed limits on individual arguments, and are not expected
     /// to work even if retried with a smaller command.
     TooLarge,
+    /// An argument or environment variable cannot be represented in the form the
+    /// platform passes to new processes, such as an unpaired surrogate on Windows.
+    InvalidEncoding,
+    /// An argument or environment variable contains an interior NUL, which cannot
+    /// be passed to a new process on any platform.
+    ContainsNul {
+        /// Whether the value is an argument or environment variable.
+        domain: Domain,
+    },
+    /// The environment has been frozen with `freeze_env` and cannot be replaced.
+    EnvFrozen,
+    /// There is no program to run, either because none was given or it is empty.
+    EmptyCommand,
+    /// There is no argument to modify, as the command has only its program and any
+    /// placeholder.
+    NoArgument,
+    /// The limits given to a constructor leave too little space for even the
+    /// program, so no command could be built with them.
+    LimitTooSmall {
+        /// The space the program needs.
+        needed: usize,
+        /// The space usable for arguments under the limits.
+        limit: usize,
+    },
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match *self {
-                Error::TooMany => "too many values",
-                Error::TooLarge => "value is too large",
-                Error::InsufficientSpace => "insufficient space for value",
+        match *self {
+            Error::TooMany { limit, domain } => {
+                write!(f, "too many values in {} (limit {})", domain, limit)
             }
-        )
+            Error::TooLarge => write!(f, "value is too large"),
+            Error::InsufficientSpace {
+                needed,
+                available,
+                domain,
+            } => write!(
+                f,
+                "insufficient space for value in {} (needed {}, {} available)",
+                domain, needed, available
+            ),
+            Error::InvalidEncoding => write!(f, "value cannot be encoded for the platform"),
+            Error::ContainsNul { domain } => write!(f, "value in {} contains a NUL", domain),
+            Error::EnvFrozen => write!(f, "environment is frozen"),
+            Error::EmptyCommand => write!(f, "no command specified"),
+            Error::NoArgument => write!(f, "no argument to modify"),
+            Error::LimitTooSmall { needed, limit } => write!(
+                f,
+                "argument limit of {} is too small for the program (needed {})",
+                limit, needed
+            ),
+        }
     }
 }
 
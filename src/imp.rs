@@ -1,16 +1,55 @@
 // A conservative fallback implementation
 
-use std::ffi::OsStr;
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use crate::posix::shell_quote;
+pub use crate::posix::ArgLenBreakdown;
 
 const ARG_MAX: usize = 4096;
 
+// Assume 8 bytes, as for Unix.
+const MAX_POINTER_SIZE: usize = 8;
+
+// The argv and envp arrays each end with a null pointer.
+const ARG_TERMINATORS: usize = 2 * MAX_POINTER_SIZE;
+
 fn osstr_len<S: AsRef<OsStr>>(arg: S) -> usize {
-    arg.as_ref().len() + 1
+    arg.as_ref().len()
+}
+
+// Without a known encoding, treat bytes as lossy UTF-8.
+pub(crate) fn bytes_to_os(bytes: &[u8]) -> OsString {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+// Without a known encoding, non-Unicode strings are replaced lossily.
+pub(crate) fn replace_osstr(s: &OsStr, from: &OsStr, to: &OsStr) -> OsString {
+    let units = |s: &OsStr| s.to_string_lossy().into_owned().into_bytes();
+    String::from_utf8_lossy(&crate::replace_units(&units(s), &units(from), &units(to)))
+        .into_owned()
+        .into()
 }
 
 pub(crate) fn arg_len<S: AsRef<OsStr>>(arg: S) -> usize {
+    arg_len_for(osstr_len(arg))
+}
+
+pub(crate) fn arg_len_for(len: usize) -> usize {
     // char * {arg}\0
-    mem::size_of::<*const c_char>() + osstr_len(arg) + 1
+    MAX_POINTER_SIZE + len + 1
+}
+
+pub(crate) fn arg_len_breakdown<S: AsRef<OsStr>>(arg: S) -> ArgLenBreakdown {
+    ArgLenBreakdown {
+        pointer_bytes: MAX_POINTER_SIZE,
+        content_bytes: osstr_len(arg),
+        terminator_bytes: 1,
+    }
 }
 
 pub(crate) fn env_pair_len(k: &OsStr, v: &OsStr) -> usize {
@@ -19,22 +58,59 @@ pub(crate) fn env_pair_len(k: &OsStr, v: &OsStr) -> usize {
 }
 
 pub(crate) fn env_key_len(k: &OsStr) -> usize {
-    mem::size_of::<*const c_char>() + osstr_len(k) + 1
+    MAX_POINTER_SIZE + osstr_len(k) + 1
 }
 
 pub(crate) fn env_val_len(v: &OsStr) -> usize {
     osstr_len(v) + 1
 }
 
+pub(crate) fn env_pair_len_for(key_len: usize, val_len: usize) -> usize {
+    MAX_POINTER_SIZE + key_len + 1 + val_len + 1
+}
+
+// Assume environment variable names are case sensitive, as for Unix.
+pub(crate) fn cmp_env_keys(a: &OsStr, b: &OsStr) -> Ordering {
+    a.cmp(b)
+}
+
+pub(crate) fn hash_env_key<H: Hasher>(key: &OsStr, state: &mut H) {
+    key.hash(state);
+}
+
+// Assume values are passed as C strings, so cannot contain NUL.
+pub(crate) fn is_valid_encoding(s: &OsStr) -> bool {
+    !s.as_encoded_bytes().contains(&0)
+}
+
+// There's no portable notion of an executable, so accept any file.
+pub(crate) fn executable_path(path: &Path) -> Option<PathBuf> {
+    path.is_file().then(|| path.to_owned())
+}
+
+// There's no known error for an over-long command.
+pub(crate) fn is_arg_list_too_long(_e: &io::Error) -> bool {
+    false
+}
+
+// Quote as for a POSIX shell, lossily.
+pub(crate) fn push_quoted_arg(line: &mut OsString, arg: &OsStr) {
+    let arg = arg.to_string_lossy();
+    line.push(&*String::from_utf8_lossy(&shell_quote(arg.as_bytes())));
+}
+
 impl Default for crate::CommandLimits {
     fn default() -> Self {
         Self {
-            arg_size: NonZeroUsize::new(ARG_MAX).unwrap(),
+            arg_size: NonZeroUsize::new(ARG_MAX - ARG_TERMINATORS).unwrap(),
             individual_arg_size: None,
             arg_count: None,
             env_size: None,
             individual_env_size: None,
             env_count: None,
+            total_size: None,
+            pointer_size: Default::default(),
+            reserve: 0,
         }
     }
 }
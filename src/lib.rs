@@ -1,45 +1,501 @@
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsString;
+use std::io;
+use std::iter::Chain;
 use std::num::NonZeroUsize;
-use std::process::Command;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::Arc;
 use std::{env, ffi::OsStr};
 
 #[cfg_attr(unix, path = "unix.rs")]
 #[cfg_attr(windows, path = "windows.rs")]
+#[cfg_attr(target_os = "wasi", path = "wasi.rs")]
 mod imp;
 
-use imp::{arg_len, env_pair_len, env_val_len};
+#[cfg(not(windows))]
+mod posix;
+
+pub use imp::ArgLenBreakdown;
+use imp::{
+    arg_len_breakdown as imp_arg_len_breakdown, bytes_to_os, executable_path, is_arg_list_too_long,
+    is_valid_encoding, push_quoted_arg, replace_osstr,
+};
+
+mod argv;
+use argv::{ArgvRange, SharedArgv};
+
+mod batch;
+pub use batch::{pack, run_parallel, CommandBatches, Packer, RunParallel};
+
+mod outcome;
+pub use outcome::{BatchOutcome, ExitPolicy};
+
+mod xargs;
+pub use xargs::{run_xargs, XargsOpts};
+
+pub mod parse;
+
+pub mod sizing;
+
+mod platform;
+pub use platform::Platform;
+
+mod utilization;
+pub use utilization::Utilization;
+
+#[cfg(feature = "serde")]
+mod profile;
+
+mod overrides;
+pub use overrides::InvalidOverride;
+
+mod sink;
+use sink::CommandSink;
+
+mod response;
+pub use response::ResponseFile;
+
+mod snapshot;
+pub use snapshot::BaseSnapshot;
+
+mod env_key;
+use env_key::EnvKey;
 
 mod error;
-pub use error::Error;
+pub use error::{Domain, Error};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// cmd.exe truncates the command lines it interprets at 8191 characters, which
+// must also hold the "cmd.exe /c" prefix it's started with.
+const CMD_SHELL_MAX: usize = 8191;
+const CMD_SHELL_RESERVED: usize = 512;
+
+// The environment block is unaffected by cmd.exe, and keeps the usual limit,
+// less its terminating null.
+const CMD_SHELL_ENV_MAX: usize = 32767 - 4096 - 1;
+
+// Most commands have only a handful of arguments, which the smallvec feature
+// keeps inline.
+#[cfg(feature = "smallvec")]
+const ARGV_INLINE: usize = 4;
+
+#[cfg(feature = "smallvec")]
+type Argv = smallvec::SmallVec<[OsString; ARGV_INLINE]>;
+
+#[cfg(not(feature = "smallvec"))]
+type Argv = Vec<OsString>;
+
+// Don't preallocate for more arguments than this, however many arg_count allows.
+// With smallvec, anything beyond the inline capacity would go straight to the heap.
+#[cfg(feature = "smallvec")]
+const ARGV_CAPACITY_MAX: usize = ARGV_INLINE;
+
+#[cfg(not(feature = "smallvec"))]
+const ARGV_CAPACITY_MAX: usize = 1024;
+
+// Return the capacity to preallocate for arguments, since arg_count bounds how
+// many there can be.
+fn argv_capacity(limits: &CommandLimits) -> usize {
+    limits
+        .arg_count
+        .map_or(0, |count| count.get().min(ARGV_CAPACITY_MAX))
+}
+
+// Don't preallocate for more environment variables than this.
+const ENV_CAPACITY_MAX: usize = 256;
+
+// Return the capacity to preallocate for environment variables set or removed,
+// since env_count bounds how many can be set.
+fn env_capacity(limits: &CommandLimits) -> usize {
+    limits
+        .env_count
+        .map_or(0, |count| count.get().min(ENV_CAPACITY_MAX))
+}
+
+/// Return a copy of `template` with every occurrence of `token` within each argument
+/// replaced by `item`, as with `xargs -I`.
+///
+/// See `CommandBuilder::args_substituted` to add the result to a command.
+pub fn substitute_args<S: AsRef<OsStr>>(
+    template: &[S],
+    token: &OsStr,
+    item: &OsStr,
+) -> Vec<OsString> {
+    template
+        .iter()
+        .map(|arg| replace_osstr(arg.as_ref(), token, item))
+        .collect()
+}
+
+// Count the UTF-16 code units of a Windows argument, and the backslashes added
+// when quoting it as `CommandLineToArgvW` expects: a run of backslashes is
+// doubled only when followed by a quote, either an embedded one, which is itself
+// escaped, or the closing quote.
+pub(crate) fn windows_arg_units<I: IntoIterator<Item = u16>>(units: I) -> (usize, usize) {
+    let mut content = 0;
+    let mut escape = 0;
+    let mut backslashes = 0;
+
+    for ch in units {
+        content += 1;
+        if ch == b'\\' as u16 {
+            backslashes += 1;
+        } else {
+            if ch == b'"' as u16 {
+                escape += backslashes + 1;
+            }
+            backslashes = 0;
+        }
+    }
+
+    (content, escape + backslashes)
+}
+
+// Replace every non-overlapping occurrence of `from` in `s` with `to`.
+pub(crate) fn replace_units<T: PartialEq + Clone>(s: &[T], from: &[T], to: &[T]) -> Vec<T> {
+    if from.is_empty() {
+        return s.to_vec();
+    }
+
+    let mut out = Vec::with_capacity(s.len());
+    let mut rest = s;
+
+    while !rest.is_empty() {
+        if rest.starts_with(from) {
+            out.extend_from_slice(to);
+            rest = &rest[from.len()..];
+        } else {
+            out.push(rest[0].clone());
+            rest = &rest[1..];
+        }
+    }
+
+    out
+}
+
+// Check a command needing `needed` in total fits within `available`.
+fn check_space(needed: usize, available: usize, domain: Domain) -> Result<()> {
+    if available < needed {
+        Err(Error::InsufficientSpace {
+            needed,
+            available,
+            domain,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+// Reject a value with an interior NUL, which no platform can pass to a new process.
+fn check_nul(s: &OsStr, domain: Domain) -> Result<()> {
+    if s.as_encoded_bytes().contains(&0) {
+        Err(Error::ContainsNul { domain })
+    } else {
+        Ok(())
+    }
+}
+
+// Remove `len` from an accounted size.  This should never exceed what was added,
+// so debug builds assert it, but release builds saturate rather than panic.
+fn release(size: usize, len: usize) -> usize {
+    debug_assert!(len <= size, "releasing {} from a size of {}", len, size);
+    size.saturating_sub(len)
+}
+
+// Remove the `len` of an inherited variable from an accounted size.  Inherited
+// variables are read again from the process environment, which may have changed
+// since they were counted, so this saturates without asserting.
+fn release_inherited(size: usize, len: usize) -> usize {
+    size.saturating_sub(len)
+}
+
+// Return the environment of this process, as it would be passed on to a command.
+// Any duplicate keys are collapsed to the last value, as when spawning with a
+// modified environment, so they aren't counted twice.
+fn current_env() -> BTreeMap<EnvKey, OsString> {
+    env::vars_os().map(|(k, v)| (k.into(), v)).collect()
+}
+
+// Serialise tests which change the process environment, or which measure the
+// inherited environment and would be upset by another test changing it.
+#[cfg(test)]
+pub(crate) fn lock_env() -> std::sync::MutexGuard<'static, ()> {
+    static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    ENV_LOCK
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+// Blame whichever of the arguments and environment takes up more of a shared limit.
+fn shared_domain(arg_size: usize, env_size: usize) -> Domain {
+    if arg_size < env_size {
+        Domain::Env
+    } else {
+        Domain::Arg
+    }
+}
+
+/// Explain how the space an argument takes on this platform is made up.
+///
+/// The fields of `ArgLenBreakdown` are platform-specific, but its `total()` always
+/// matches the size used when adding the argument to a `CommandBuilder`.
+pub fn arg_len_breakdown<S: AsRef<OsStr>>(arg: S) -> ArgLenBreakdown {
+    imp_arg_len_breakdown(arg)
+}
+
+/// Check whether a command made of `argv` and exactly the environment `env` would
+/// fit within `limits` on this platform, without spawning it.
+///
+/// This adds each argument and variable to an empty-environment `CommandBuilder`,
+/// so makes exactly the same checks, returning `Error::TooLarge` for an item over
+/// an individual limit, `Error::TooMany` for too many arguments or variables, and
+/// `Error::InsufficientSpace` if they don't fit together.  As with `env`, a later
+/// value for the same key replaces an earlier one.  Nothing is inherited, so `env`
+/// should include any variables from the current process which are to be passed
+/// on.  Returns `Error::EmptyCommand` if there's no program, and `Error::TooLarge`
+/// if `limits` leave no room even for that.
+pub fn check_plan(
+    limits: &CommandLimits,
+    argv: &[OsString],
+    env: &[(OsString, OsString)],
+) -> Result<()> {
+    let (program, args) = argv.split_first().ok_or(Error::EmptyCommand)?;
+
+    let mut cmd = CommandBuilder::empty_env_with_limits(program, *limits).map_err(|e| match e {
+        Error::LimitTooSmall { .. } => Error::TooLarge,
+        e => e,
+    })?;
+    cmd.args(args)?;
+    for (key, val) in env {
+        cmd.env(key, val)?;
+    }
+    Ok(())
+}
+
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CommandLimits {
     /// The maximum byte/character length for command arguments.
     pub arg_size: NonZeroUsize,
-    /// The maximum size of an individual command-line argument.
+    /// The maximum size of an individual command-line argument.  By default this
+    /// is set only on Linux, which caps each string at 32 pages.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, deserialize_with = "profile::zero_as_none")
+    )]
     pub individual_arg_size: Option<NonZeroUsize>,
-    /// The total number of arguments that are permitted.
+    /// The total number of arguments that are permitted, including the program
+    /// itself and any given before the arguments being batched.  To limit only the
+    /// arguments added from some point, as with `xargs -n`, see
+    /// `CommandBuilder::set_max_data_args`.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, deserialize_with = "profile::zero_as_none")
+    )]
     pub arg_count: Option<NonZeroUsize>,
     /// The maximum byte/character length for a command's environment variables.
+    ///
+    /// If set, as on Windows, the environment and arguments are separate pools, so
+    /// filling either never rejects additions to the other.  If unset, as on Unix,
+    /// the environment shares `arg_size` with the arguments.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, deserialize_with = "profile::zero_as_none")
+    )]
     pub env_size: Option<NonZeroUsize>,
     /// The maximum byte/character length for individual key=value pairs in the
     /// environment.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, deserialize_with = "profile::zero_as_none")
+    )]
     pub individual_env_size: Option<NonZeroUsize>,
     /// The maximum number of key=value pairs allowed in the environment.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, deserialize_with = "profile::zero_as_none")
+    )]
     pub env_count: Option<NonZeroUsize>,
+    /// The maximum byte/character length for the arguments and environment
+    /// together, checked before `arg_size` and `env_size`.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, deserialize_with = "profile::zero_as_none")
+    )]
+    pub total_size: Option<NonZeroUsize>,
+    /// The size of pointers in the argument and environment arrays on Unix.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub pointer_size: PointerSize,
+    /// Extra space to leave unused in `arg_size`, `env_size` and `total_size`, as a cushion
+    /// against kernels which account for them slightly differently.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reserve: usize,
+}
+
+/// The size assumed for each pointer in the argument and environment arrays passed
+/// to a new process on Unix.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PointerSize {
+    /// The pointer size of this process, which is only safe if the programs run
+    /// are the same width.
+    Native,
+    /// 8 bytes, which is safe even for a 32-bit process running on a 64-bit kernel
+    /// and inheriting its limits.
+    #[default]
+    Wide,
+}
+
+impl PointerSize {
+    /// Return the size of a pointer in bytes.
+    pub const fn bytes(self) -> usize {
+        match self {
+            PointerSize::Native => std::mem::size_of::<*const u8>(),
+            PointerSize::Wide => 8,
+        }
+    }
+}
+
+impl CommandLimits {
+    /// Return the most UTF-16 code units an argument of `raw_len` characters could
+    /// occupy on a Windows command line.
+    ///
+    /// This assumes every character is a backslash or quote which needs escaping,
+    /// plus surrounding quotes and a separator, so is an upper bound usable before
+    /// the argument itself is known.
+    pub const fn windows_worst_case_arg_len(raw_len: usize) -> usize {
+        raw_len * 2 + 3
+    }
+
+    /// Return limits allowing `arg_size` bytes/characters for arguments and the
+    /// environment combined, with no other limits.
+    ///
+    /// Unlike `CommandLimits::default()` this does not depend on the system, so is
+    /// the recommended way to write tests which assert exact sizes, including with
+    /// `CommandBuilder::reinterpret_for` to test another platform.
+    pub const fn fixed(arg_size: NonZeroUsize) -> Self {
+        Self {
+            arg_size,
+            individual_arg_size: None,
+            arg_count: None,
+            env_size: None,
+            individual_env_size: None,
+            env_count: None,
+            total_size: None,
+            pointer_size: PointerSize::Wide,
+            reserve: 0,
+        }
+    }
+
+    /// Return the default limits, additionally checked against the stack limit in
+    /// `/proc/self/limits` on Linux, using whichever is smaller.
+    ///
+    /// This guards against `getrlimit` disagreeing with the kernel, as in some
+    /// containers.  If `/proc` is unavailable this is the same as `default()`.
+    pub fn detect() -> Self {
+        #[cfg(unix)]
+        {
+            imp::detect_limits()
+        }
+        #[cfg(not(unix))]
+        {
+            Self::default()
+        }
+    }
+
+    /// Return these limits with `extra` bytes/characters of `arg_size` and
+    /// `env_size` left unused.
+    pub fn with_reserve(mut self, extra: usize) -> Self {
+        self.reserve = extra;
+        self
+    }
+
+    // The space usable for arguments, and for the environment if env_size is unset.
+    fn usable_arg_size(&self) -> usize {
+        self.arg_size.get().saturating_sub(self.reserve)
+    }
+
+    // The space usable for the environment, if it has a separate limit.
+    fn usable_env_size(&self) -> Option<usize> {
+        self.env_size
+            .map(|env_size| env_size.get().saturating_sub(self.reserve))
+    }
+
+    // The space usable for the arguments and environment together, if limited.
+    fn usable_total_size(&self) -> Option<usize> {
+        self.total_size
+            .map(|total_size| total_size.get().saturating_sub(self.reserve))
+    }
+
+    /// Return limits for a command line which will be interpreted by `cmd.exe /c`,
+    /// which cannot exceed 8191 characters.
+    ///
+    /// This is only needed when commands pass through the Windows shell, such as
+    /// for batch files.  Processes created directly are allowed the far larger
+    /// `CreateProcess` limit given by `CommandLimits::default()`.
+    pub fn windows_cmd_shell() -> Self {
+        Self {
+            arg_size: NonZeroUsize::new(CMD_SHELL_MAX - CMD_SHELL_RESERVED).unwrap(),
+            individual_arg_size: None,
+            arg_count: None,
+            env_size: NonZeroUsize::new(CMD_SHELL_ENV_MAX),
+            individual_env_size: None,
+            env_count: None,
+            total_size: None,
+            pointer_size: Default::default(),
+            reserve: 0,
+        }
+    }
+}
+
+/// An individual limit within `CommandLimits` that may prevent a command growing.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Constraint {
+    /// `CommandLimits::arg_size`, which includes the environment if `env_size` is unset.
+    ArgSize,
+    /// `CommandLimits::arg_count`
+    ArgCount,
+    /// `CommandLimits::env_size`
+    EnvSize,
+    /// `CommandLimits::env_count`
+    EnvCount,
+    /// `CommandLimits::total_size`
+    TotalSize,
 }
 
+// Variables set or removed on a command.  Hashed for fast lookups, since order
+// only matters to `get_envs`, and `Command` sorts them itself.  Held in an `Arc` on
+// the builder so clones share it until one changes a variable.
+type EnvMap = HashMap<EnvKey, Option<OsString>>;
+
+/// A builder for `Command`s which checks arguments and environment variables fit
+/// within `CommandLimits` as they are added.
+///
+/// The first element of the argument list is always the program, which every
+/// constructor adds, so it is never empty.
 #[derive(Debug, Clone)]
 pub struct CommandBuilder {
     limits: CommandLimits,
-    argv: Vec<OsString>,
-    env: BTreeMap<OsString, Option<OsString>>,
+    platform: Platform,
+    argv: SharedArgv,
+    env: Arc<EnvMap>,
     arg_size: usize,
     env_size: usize,
     clear_env: bool,
+    env_frozen: bool,
+    /// The index of the placeholder argument, and the number of arguments which
+    /// preceded any replacements.
+    placeholder: Option<(usize, usize)>,
+    current_dir: Option<PathBuf>,
+    /// Argument space set aside for a wrapper, counted in arg_size.
+    wrapper_size: usize,
+    /// Arguments indexed by `arg_unique`, and the length of argv they cover.
+    seen_args: HashSet<OsString>,
+    seen_len: usize,
 }
 
 impl CommandBuilder {
@@ -71,20 +527,31 @@ impl CommandBuilder {
         Self::capture_with_limits(command, Default::default())
     }
 
+    /// Create a new `CommandBuilder` from `argv`, with the first element as the
+    /// program and the rest as its arguments, inheriting the environment as with
+    /// `new`.
+    ///
+    /// Returns `Error::EmptyCommand` if `argv` is empty.
+    pub fn from_argv<S>(argv: &[S]) -> Result<Self>
+    where
+        S: AsRef<OsStr>,
+    {
+        let (program, args) = argv.split_first().ok_or(Error::EmptyCommand)?;
+        let mut cmd = Self::new(program)?;
+        cmd.args(args)?;
+        Ok(cmd)
+    }
+
     /// Create a new `CommandBuilder` with specified limits.
+    ///
+    /// Returns `Error::EmptyCommand` if `command` is empty, or
+    /// `Error::LimitTooSmall` if `limits` leave no room for it, as with every
+    /// constructor.
     pub fn with_limits<S>(command: S, limits: CommandLimits) -> Result<Self>
     where
         S: AsRef<OsStr>,
     {
-        let mut cmd = Self {
-            limits,
-            argv: Default::default(),
-            env: Default::default(),
-            arg_size: Default::default(),
-            env_size: Default::default(),
-            clear_env: Default::default(),
-        };
-
+        let mut cmd = Self::without_env(&command, limits)?;
         cmd.inherit_env()?;
         cmd.arg(command)?;
         Ok(cmd)
@@ -95,18 +562,66 @@ impl CommandBuilder {
     where
         S: AsRef<OsStr>,
     {
-        let mut cmd = Self {
+        let mut cmd = Self::without_env(&command, limits)?;
+        cmd.capture_env()?;
+        cmd.arg(command)?;
+        Ok(cmd)
+    }
+
+    /// Create a new `CommandBuilder` for the given `command` with an empty
+    /// environment, as if `env_clear` had been called, without reading the current
+    /// environment at all.
+    pub fn new_empty_env<S>(command: S) -> Result<Self>
+    where
+        S: AsRef<OsStr>,
+    {
+        Self::empty_env_with_limits(command, Default::default())
+    }
+
+    /// Create a new `CommandBuilder` with specified limits and an empty environment.
+    pub fn empty_env_with_limits<S>(command: S, limits: CommandLimits) -> Result<Self>
+    where
+        S: AsRef<OsStr>,
+    {
+        let mut cmd = Self::without_env(&command, limits)?;
+        cmd.env_clear()?;
+        cmd.arg(command)?;
+        Ok(cmd)
+    }
+
+    // Create a builder with no program or environment settings yet, checking
+    // `command` isn't empty and the limits can hold it.
+    fn without_env<S>(command: S, limits: CommandLimits) -> Result<Self>
+    where
+        S: AsRef<OsStr>,
+    {
+        if command.as_ref().is_empty() {
+            return Err(Error::EmptyCommand);
+        }
+
+        let needed = Platform::default().arg_len(command.as_ref(), limits.pointer_size);
+        let limit = limits
+            .usable_arg_size()
+            .min(limits.usable_total_size().unwrap_or(usize::MAX));
+        if limit < needed {
+            return Err(Error::LimitTooSmall { needed, limit });
+        }
+
+        Ok(Self {
             limits,
-            argv: Default::default(),
-            env: Default::default(),
+            platform: Default::default(),
+            argv: SharedArgv::with_capacity(argv_capacity(&limits)),
+            env: Arc::new(EnvMap::with_capacity(env_capacity(&limits))),
             arg_size: Default::default(),
             env_size: Default::default(),
             clear_env: Default::default(),
-        };
-
-        cmd.capture_env()?;
-        cmd.arg(command)?;
-        Ok(cmd)
+            env_frozen: Default::default(),
+            placeholder: Default::default(),
+            current_dir: Default::default(),
+            wrapper_size: Default::default(),
+            seen_args: Default::default(),
+            seen_len: Default::default(),
+        })
     }
 
     /// Clear any previous env settings and restore the default behaviour of inheriting
@@ -114,8 +629,13 @@ impl CommandBuilder {
     ///
     /// If the environment would be too large to fit, it returns `Err`.
     pub fn inherit_env(&mut self) -> Result<&mut Self> {
+        self.check_env_frozen()?;
+
         let old_env_size = self.env_size;
-        self.env_size = env::vars_os().map(|(k, v)| env_pair_len(&k, &v)).sum();
+        self.env_size = current_env()
+            .iter()
+            .map(|(k, v)| self.env_pair_len(k, v))
+            .sum();
 
         if let Err(e) = self.check_env_size(0) {
             self.env_size = old_env_size;
@@ -123,7 +643,7 @@ impl CommandBuilder {
         }
 
         self.clear_env = false;
-        self.env.clear();
+        self.clear_env_map();
         Ok(self)
     }
 
@@ -133,260 +653,2888 @@ impl CommandBuilder {
     ///
     /// This clears any previously set or removed env variables for this instance.
     ///
-    /// If the environment would be too large to fit, it returns `Err`.
+    /// If the environment would not fit, it returns `Err`.
     pub fn capture_env(&mut self) -> Result<&mut Self> {
-        let old_env_size = self.env_size;
-        self.env_size = 0;
+        self.check_env_frozen()?;
 
-        let env: BTreeMap<OsString, Option<OsString>> = env::vars_os()
-            .inspect(|(k, v)| self.env_size += env_pair_len(k, v))
-            .map(|(k, v)| (k, Some(v)))
-            .collect();
+        let env = current_env().into_iter().map(|(k, v)| (k, Some(v)));
+        self.replace_env(env.collect())?;
+        Ok(self)
+    }
+
+    /// Capture at most `max` variables from the environment into this `CommandBuilder`,
+    /// clearing the rest.
+    ///
+    /// Variables are selected in key order, so the same environment always yields the
+    /// same subset.
+    ///
+    /// This clears any previously set or removed env variables for this instance.
+    ///
+    /// If the captured variables would not fit, it returns `Err`.
+    pub fn inherit_env_limited(&mut self, max: usize) -> Result<&mut Self> {
+        self.check_env_frozen()?;
+
+        let env = current_env()
+            .into_iter()
+            .take(max)
+            .map(|(k, v)| (k, Some(v)));
+        self.replace_env(env.collect())?;
+        Ok(self)
+    }
+
+    /// Capture the current effective environment into this `CommandBuilder`, and
+    /// prevent it from being replaced by future calls to `inherit_env`, `capture_env`,
+    /// `inherit_env_limited` or `env_clear`, which will return `Error::EnvFrozen`.
+    ///
+    /// Individual variables may still be changed with `env` and `env_remove`.
+    /// `reset_to` and `restore` return the environment to the state they were given,
+    /// including whether it was frozen.
+    ///
+    /// Once captured every variable counts towards `CommandLimits::env_count`, so if
+    /// the environment would not fit it returns `Err` and is left unfrozen.
+    pub fn freeze_env(&mut self) -> Result<&mut Self> {
+        let env = self.effective_env().into_iter().map(|(k, v)| (k, Some(v)));
+        self.replace_env(env.collect())?;
+        self.env_frozen = true;
+        Ok(self)
+    }
+
+    // Replace the environment with exactly `env`, if it fits within the limits on
+    // individual variables, their number, and their total size.
+    fn replace_env(&mut self, env: EnvMap) -> Result<()> {
+        let mut env_size = 0;
+        for (k, v) in env.iter() {
+            if let Some(v) = v {
+                let len = self.env_pair_len(k, v);
+                self.check_env_pair_len(len)?;
+                env_size += len;
+            }
+        }
+
+        if let Some(limit) = self.limits.env_count {
+            if limit.get() < env.len() {
+                return Err(Error::TooMany {
+                    limit: limit.get(),
+                    domain: Domain::Env,
+                });
+            }
+        }
 
+        let old_env_size = std::mem::replace(&mut self.env_size, env_size);
         if let Err(e) = self.check_env_size(0) {
             self.env_size = old_env_size;
             return Err(e);
         }
 
         self.clear_env = true;
-        self.env = env;
-        Ok(self)
+        self.env = Arc::new(env);
+        Ok(())
     }
 
-    /// Check the current command has space for `size` more environment data.
-    fn check_env_size(&self, size: usize) -> Result<()> {
-        // If the env limit is set, check against that
-        if let Some(env_limit) = self.limits.env_size {
-            if env_limit.get() < self.env_size + size {
-                return Err(Error::InsufficientSpace);
+    /// Return the environment the command would be spawned with.
+    fn effective_env(&self) -> BTreeMap<EnvKey, OsString> {
+        let mut env = if self.clear_env {
+            BTreeMap::new()
+        } else {
+            current_env()
+        };
+
+        for (k, v) in self.env.iter() {
+            if let Some(v) = v {
+                env.insert(k.clone(), v.clone());
+            } else {
+                env.remove(k);
             }
-        } else if self.limits.arg_size.get() < self.arg_size + self.env_size + size {
-            return Err(Error::InsufficientSpace);
         }
 
-        Ok(())
+        env
     }
 
-    fn check_env_pair(&self, key: &OsStr, val: &OsStr) -> Result<usize> {
-        let len = env_pair_len(key, val);
+    fn arg_len(&self, arg: &OsStr) -> usize {
+        self.platform.arg_len(arg, self.limits.pointer_size)
+    }
 
-        if self
-            .limits
-            .individual_env_size
-            .or(self.limits.env_size)
-            .unwrap_or(self.limits.arg_size)
-            .get()
-            < len
-        {
-            return Err(Error::TooLarge);
+    fn arg_len_for(&self, len: usize) -> usize {
+        self.platform.arg_len_for(len, self.limits.pointer_size)
+    }
+
+    fn env_pair_len(&self, key: &OsStr, val: &OsStr) -> usize {
+        self.platform
+            .env_pair_len(key, val, self.limits.pointer_size)
+    }
+
+    fn env_pair_len_for(&self, key_len: usize, val_len: usize) -> usize {
+        self.platform
+            .env_pair_len_for(key_len, val_len, self.limits.pointer_size)
+    }
+
+    fn check_env_frozen(&self) -> Result<()> {
+        if self.env_frozen {
+            Err(Error::EnvFrozen)
+        } else {
+            Ok(())
         }
+    }
 
-        if self
-            .limits
-            .env_count
-            .map(|limit| limit.get() <= self.env.len())
-            .unwrap_or(false)
-        {
-            return Err(Error::TooMany);
+    /// Re-measure the existing arguments and environment using the conventions of
+    /// `platform`, and check them against `limits`.
+    ///
+    /// Subsequent additions are also measured for `platform`.  If the command does not
+    /// fit, the builder is left unchanged and `Err` is returned.
+    pub fn reinterpret_for(
+        &mut self,
+        limits: CommandLimits,
+        platform: Platform,
+    ) -> Result<&mut Self> {
+        let old = (self.limits, self.platform, self.arg_size, self.env_size);
+
+        self.limits = limits;
+        self.platform = platform;
+        self.arg_size = self.measure_args();
+        self.env_size = self
+            .effective_env()
+            .iter()
+            .map(|(k, v)| self.env_pair_len(k, v))
+            .sum();
+
+        if let Err(e) = self.check_usage() {
+            (self.limits, self.platform, self.arg_size, self.env_size) = old;
+            return Err(e);
         }
 
-        self.check_env_size(len).map(|_| len)
+        Ok(self)
     }
 
-    /// Check if the given argument will accomodate our limits.
+    /// Set aside `bytes` of argument space for a wrapper the command will be run
+    /// through, such as `sh -c` or `env VAR=val`, as if an argument of that size
+    /// had been added.
     ///
-    /// Return an appropriate `Error` case or `Ok(size)` giving the number this
-    /// would add to arg_size.
-    fn check_arg(&self, arg: &OsStr) -> Result<usize> {
-        let len = arg_len(arg);
+    /// This is counted in `arg_size`, and repeated calls add to it.  If the current
+    /// contents would no longer fit, the builder is left unchanged and `Err` is
+    /// returned.
+    pub fn reserve_for_wrapper(&mut self, bytes: usize) -> Result<&mut Self> {
+        self.check_arg_space(bytes)?;
+        self.wrapper_size += bytes;
+        self.arg_size += bytes;
+        Ok(self)
+    }
 
-        if self
-            .limits
-            .individual_arg_size
-            .unwrap_or(self.limits.arg_size)
-            .get()
-            < len
-        {
-            return Err(Error::TooLarge);
-        }
+    /// Return the platform conventions used to measure this command.
+    pub fn get_platform(&self) -> Platform {
+        self.platform
+    }
 
-        if self
-            .limits
-            .arg_count
-            .map(|limit| limit.get() <= self.argv.len())
-            .unwrap_or(false)
-        {
-            return Err(Error::TooMany);
+    /// Replace the limits of this command, checking its current contents against
+    /// them.
+    ///
+    /// If the command does not fit, the builder is left unchanged and `Err` is
+    /// returned.
+    pub fn set_limits(&mut self, limits: CommandLimits) -> Result<&mut Self> {
+        // Only a change in pointer size changes how the contents are measured
+        if limits.pointer_size != self.limits.pointer_size {
+            return self.reinterpret_for(limits, self.platform);
         }
 
-        // if env and arg space is unified, we need to check both against arg_size
-        if self.limits.env_size.is_some() {
-            if self.limits.arg_size.get() < self.arg_size + len {
-                return Err(Error::InsufficientSpace);
-            }
-        } else if self.limits.arg_size.get() < self.arg_size + self.env_size + len {
-            return Err(Error::InsufficientSpace);
+        let old = std::mem::replace(&mut self.limits, limits);
+        if let Err(e) = self.check_usage() {
+            self.limits = old;
+            return Err(e);
         }
 
-        Ok(len)
+        Ok(self)
     }
 
-    /// Add the given argument to the command list if it fits.
-    pub fn arg<S>(&mut self, arg: S) -> Result<&mut Self>
-    where
-        S: AsRef<OsStr>,
-    {
-        self.arg_size += self.check_arg(arg.as_ref())?;
-        self.argv.push(arg.as_ref().to_owned());
-        Ok(self)
+    /// Set `CommandLimits::arg_size`, as with `set_limits`.
+    pub fn set_arg_size(&mut self, arg_size: NonZeroUsize) -> Result<&mut Self> {
+        self.set_limits(CommandLimits {
+            arg_size,
+            ..self.limits
+        })
     }
 
-    /// Add the provided list of arguments to the command if they all fit.
-    ///
-    /// If the entire list does not fit, no arguments are added.
-    pub fn args<S>(&mut self, args: &[S]) -> Result<&mut Self>
-    where
-        S: AsRef<OsStr>,
-    {
-        self.arg_size += args
-            .iter()
-            .map(|arg| self.check_arg(arg.as_ref()))
-            .sum::<Result<usize>>()?;
-        self.argv
-            .extend(args.iter().map(|arg| arg.as_ref().to_owned()));
-        Ok(self)
+    /// Set `CommandLimits::individual_arg_size`, as with `set_limits`.
+    pub fn set_individual_arg_size(
+        &mut self,
+        individual_arg_size: Option<NonZeroUsize>,
+    ) -> Result<&mut Self> {
+        self.set_limits(CommandLimits {
+            individual_arg_size,
+            ..self.limits
+        })
     }
 
-    /// Set the given environment variable, if it will fit.
-    pub fn env<K, V>(&mut self, key: K, value: V) -> Result<&mut Self>
+    /// Set `CommandLimits::arg_count`, as with `set_limits`.
+    pub fn set_arg_count(&mut self, arg_count: Option<NonZeroUsize>) -> Result<&mut Self> {
+        self.set_limits(CommandLimits {
+            arg_count,
+            ..self.limits
+        })
+    }
+
+    /// Allow at most `max` more arguments to be added, in addition to the program
+    /// and any arguments already present, as with `xargs -n`.
+    ///
+    /// This sets `CommandLimits::arg_count`, which counts every argument, to allow
+    /// for those present now.  `None` removes the limit.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// # use std::num::NonZeroUsize;
+    /// let mut cmd = CommandBuilder::new("grep")?;
+    /// cmd.arg("-e")?.arg("pattern")?;
+    /// cmd.set_max_data_args(NonZeroUsize::new(2))?;
+    /// assert_eq!(cmd.get_limits().arg_count, NonZeroUsize::new(5));
+    ///
+    /// cmd.arg("a")?.arg("b")?;
+    /// assert!(cmd.arg("c").is_err());
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn set_max_data_args(&mut self, max: Option<NonZeroUsize>) -> Result<&mut Self> {
+        self.set_arg_count(max.map(|max| max.saturating_add(self.arg_count())))
+    }
+
+    /// Set `CommandLimits::env_size`, as with `set_limits`.
+    pub fn set_env_size(&mut self, env_size: Option<NonZeroUsize>) -> Result<&mut Self> {
+        self.set_limits(CommandLimits {
+            env_size,
+            ..self.limits
+        })
+    }
+
+    /// Set `CommandLimits::individual_env_size`, as with `set_limits`.
+    pub fn set_individual_env_size(
+        &mut self,
+        individual_env_size: Option<NonZeroUsize>,
+    ) -> Result<&mut Self> {
+        self.set_limits(CommandLimits {
+            individual_env_size,
+            ..self.limits
+        })
+    }
+
+    /// Set `CommandLimits::env_count`, as with `set_limits`.
+    pub fn set_env_count(&mut self, env_count: Option<NonZeroUsize>) -> Result<&mut Self> {
+        self.set_limits(CommandLimits {
+            env_count,
+            ..self.limits
+        })
+    }
+
+    /// Lower `CommandLimits::arg_size` to `new_max`, as with `set_arg_size`, such as
+    /// after a spawn fails with `E2BIG` despite the command appearing to fit.
+    ///
+    /// A `new_max` above the current limit leaves it unchanged.
+    pub fn shrink_arg_size_to(&mut self, new_max: NonZeroUsize) -> Result<&mut Self> {
+        self.set_arg_size(new_max.min(self.limits.arg_size))
+    }
+
+    /// Return this command with its `arg_size` halved, for retrying with a smaller
+    /// command in an auto-tuning loop.
+    ///
+    /// Returns `None` if the limit is already 1, or if the current contents don't
+    /// fit within the halved limit.
+    pub fn retry_smaller(mut self) -> Option<CommandBuilder> {
+        let half = NonZeroUsize::new(self.limits.arg_size.get() / 2)?;
+        self.shrink_arg_size_to(half).ok()?;
+        Some(self)
+    }
+
+    /// Check the current contents of the command against its limits.
+    fn check_usage(&self) -> Result<()> {
+        let limits = &self.limits;
+
+        if let Some(limit) = limits.arg_count {
+            if limit.get() < self.arg_count() {
+                return Err(Error::TooMany {
+                    limit: limit.get(),
+                    domain: Domain::Arg,
+                });
+            }
+        }
+
+        if let Some(limit) = limits.env_count {
+            if limit.get() < self.env_var_count() {
+                return Err(Error::TooMany {
+                    limit: limit.get(),
+                    domain: Domain::Env,
+                });
+            }
+        }
+
+        let arg_limit = limits.individual_arg_size.unwrap_or(limits.arg_size).get();
+        let env_limit = limits
+            .individual_env_size
+            .or(limits.env_size)
+            .unwrap_or(limits.arg_size)
+            .get();
+
+        if self.into_iter().any(|arg| arg_limit < self.arg_len(arg))
+            || self.env.iter().any(|(k, v)| {
+                v.as_ref()
+                    .map(|v| env_limit < self.env_pair_len(k, v))
+                    .unwrap_or(false)
+            })
+        {
+            return Err(Error::TooLarge);
+        }
+
+        self.check_arg_space(0)?;
+        self.check_env_size(0)
+    }
+
+    /// Check the current command has space for `size` more environment data.
+    fn check_env_size(&self, size: usize) -> Result<()> {
+        if let Some(total_limit) = self.limits.usable_total_size() {
+            check_space(
+                self.arg_size + self.env_size + size,
+                total_limit,
+                shared_domain(self.arg_size, self.env_size + size),
+            )?;
+        }
+
+        // If the env limit is set, check against that
+        match self.limits.usable_env_size() {
+            Some(env_limit) => check_space(self.env_size + size, env_limit, Domain::Env),
+            None => check_space(
+                self.arg_size + self.env_size + size,
+                self.limits.usable_arg_size(),
+                shared_domain(self.arg_size, self.env_size + size),
+            ),
+        }
+    }
+
+    /// Return the number of arguments which will be passed, including the program
+    /// but not any placeholder, which is what `arg_count` limits.
+    fn arg_count(&self) -> usize {
+        self.argv.len() - usize::from(self.placeholder.is_some())
+    }
+
+    /// Return the number of variables set on this command, which is what `env_count`
+    /// limits.
+    fn env_var_count(&self) -> usize {
+        self.env.values().filter(|v| v.is_some()).count()
+    }
+
+    /// Check a key=value pair of `len` is within the limit on individual pairs.
+    fn check_env_pair_len(&self, len: usize) -> Result<()> {
+        if self
+            .limits
+            .individual_env_size
+            .or(self.limits.env_size)
+            .unwrap_or(self.limits.arg_size)
+            .get()
+            < len
+        {
+            return Err(Error::TooLarge);
+        }
+
+        Ok(())
+    }
+
+    fn check_env_pair(&self, key: &OsStr, val: &OsStr) -> Result<usize> {
+        let len = self.env_pair_len(key, val);
+        self.check_env_pair_len(len)?;
+
+        // Removals don't count, so only count those set if the map could be full
+        if let Some(limit) = self.limits.env_count {
+            if limit.get() <= self.env.len() && limit.get() <= self.env_var_count() {
+                return Err(Error::TooMany {
+                    limit: limit.get(),
+                    domain: Domain::Env,
+                });
+            }
+        }
+
+        self.check_env_size(len).map(|_| len)
+    }
+
+    /// Check if the given argument will accomodate our limits.
+    ///
+    /// Return an appropriate `Error` case or `Ok(size)` giving the number this
+    /// would add to arg_size.
+    fn check_arg(&self, arg: &OsStr) -> Result<usize> {
+        check_nul(arg, Domain::Arg)?;
+        self.check_arg_len(self.arg_len(arg))
+    }
+
+    /// As `check_arg`, for an argument accounting for `len`.
+    fn check_arg_len(&self, len: usize) -> Result<usize> {
+        if self
+            .limits
+            .individual_arg_size
+            .unwrap_or(self.limits.arg_size)
+            .get()
+            < len
+        {
+            return Err(Error::TooLarge);
+        }
+
+        if let Some(limit) = self.limits.arg_count {
+            if limit.get() <= self.arg_count() {
+                return Err(Error::TooMany {
+                    limit: limit.get(),
+                    domain: Domain::Arg,
+                });
+            }
+        }
+
+        self.check_arg_space(len).map(|_| len)
+    }
+
+    /// Check the given arguments could all be added together.
+    ///
+    /// Return an appropriate `Error` case or `Ok(size)` giving the number they
+    /// would add to arg_size.
+    fn check_args<'a, I>(&self, args: I) -> Result<usize>
     where
-        K: AsRef<OsStr>,
-        V: AsRef<OsStr>,
+        I: IntoIterator<Item = &'a OsStr>,
     {
-        if let Some(old_value) = self.env.get(key.as_ref()) {
-            // If it was previously set in the command, do we have space to exchange
-            // the old value for the new one?
-            if let Some(old_value) = old_value {
-                let old_size = env_val_len(old_value);
-                let new_size = env_val_len(value.as_ref());
-                if old_size < new_size {
-                    // TODO: check individual env size limit
-                    self.check_env_size(new_size - old_size)?;
+        let mut size = 0;
+
+        for (added, arg) in args.into_iter().enumerate() {
+            check_nul(arg, Domain::Arg)?;
+            let len = self.arg_len(arg);
+
+            if self
+                .limits
+                .individual_arg_size
+                .unwrap_or(self.limits.arg_size)
+                .get()
+                < len
+            {
+                return Err(Error::TooLarge);
+            }
+
+            if let Some(limit) = self.limits.arg_count {
+                if limit.get() <= self.arg_count() + added {
+                    return Err(Error::TooMany {
+                        limit: limit.get(),
+                        domain: Domain::Arg,
+                    });
                 }
-                self.env_size = self.env_size.saturating_sub(old_size);
-            }
-        } else if let Some(old_value) = env::var_os(&key) {
-            // Ditto if it instead exists in the inherited env and wasn't previously unset
-            // FIXME: this needs a guard on self.clear_env
-            let old_size = env_val_len(&old_value);
-            let new_size = env_val_len(value.as_ref());
-            if old_size < new_size {
-                // TODO: check individual env size limit
-                self.check_env_size(new_size - old_size)?;
-            }
-            self.env_size = self.env_size.saturating_sub(old_size);
-        } else {
-            // TODO: check env count limit
-            self.env_size += self.check_env_pair(key.as_ref(), value.as_ref())?;
+            }
+
+            size += len;
+            self.check_arg_space(size)?;
         }
 
-        self.env
-            .insert(key.as_ref().to_owned(), Some(value.as_ref().to_owned()));
+        Ok(size)
+    }
 
-        Ok(self)
+    /// Check the current command has space for `size` more argument data.
+    fn check_arg_space(&self, size: usize) -> Result<()> {
+        if let Some(total_limit) = self.limits.usable_total_size() {
+            check_space(
+                self.arg_size + self.env_size + size,
+                total_limit,
+                shared_domain(self.arg_size + size, self.env_size),
+            )?;
+        }
+
+        // if env and arg space is unified, we need to check both against arg_size
+        if self.limits.env_size.is_some() {
+            check_space(
+                self.arg_size + size,
+                self.limits.usable_arg_size(),
+                Domain::Arg,
+            )
+        } else {
+            check_space(
+                self.arg_size + self.env_size + size,
+                self.limits.usable_arg_size(),
+                shared_domain(self.arg_size + size, self.env_size),
+            )
+        }
     }
 
-    /// Remove the given env variable
+    /// Split `args` across as few `Command`s as possible, each a copy of this one
+    /// with as many of the arguments appended as will fit.
     ///
-    /// This ensures the variable is not set even if it's added to the global environment later.
-    pub fn env_remove<K>(&mut self, key: K) -> &mut Self
+    /// If an argument cannot fit even in an otherwise empty command, such as one
+    /// returning `Error::TooLarge`, the error is yielded after the preceding commands
+    /// and iteration ends.
+    pub fn batch<I, S>(&self, args: I) -> CommandBatches<'_, I::IntoIter, S>
     where
-        K: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
     {
-        if let Some(value) = self.env.get(key.as_ref()) {
-            if let Some(value) = value {
-                self.env_size = self
-                    .env_size
-                    .saturating_sub(env_pair_len(key.as_ref(), value));
-            } else {
-                // If it's already been set to None, do nothing instead of reinserting
-                return self;
+        CommandBatches::new(self, args.into_iter())
+    }
+
+    /// Return the indices of any `items` which could not be added to this command
+    /// even on their own, and so cannot be passed by any batch built from it.
+    ///
+    /// Each item is measured against this command as it stands, which is what
+    /// `batch` and `pack` start every batch from, so arguments already added take
+    /// space from it.  Items which only fail to fit alongside each other are not
+    /// included.
+    pub fn find_impossible_items<S: AsRef<OsStr>>(&self, items: &[S]) -> Vec<usize> {
+        items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| self.check_arg(item.as_ref()).is_err())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Return how many more arguments each accounting for `len` could be added.
+    fn arg_capacity(&self, len: usize) -> usize {
+        if self
+            .limits
+            .individual_arg_size
+            .unwrap_or(self.limits.arg_size)
+            .get()
+            < len
+        {
+            return 0;
+        }
+
+        let by_count = self
+            .limits
+            .arg_count
+            .map(|limit| limit.get().saturating_sub(self.arg_count()))
+            .unwrap_or(usize::MAX);
+
+        (self.remaining_arg_space() / len).min(by_count)
+    }
+
+    /// Return every limit which is at capacity, such that not even an empty argument
+    /// or environment variable could be added against it.
+    pub fn binding_constraints(&self) -> Vec<Constraint> {
+        let mut constraints = vec![];
+
+        let arg_used = if self.limits.env_size.is_some() {
+            self.arg_size
+        } else {
+            self.arg_size + self.env_size
+        };
+
+        if self.limits.usable_arg_size() < arg_used + self.arg_len_for(0) {
+            constraints.push(Constraint::ArgSize);
+        }
+
+        if let Some(limit) = self.limits.arg_count {
+            if limit.get() <= self.arg_count() {
+                constraints.push(Constraint::ArgCount);
             }
-        } else if !self.clear_env {
-            if let Some(value) = env::var_os(key.as_ref()) {
-                self.env_size = self
-                    .env_size
-                    .saturating_sub(env_pair_len(key.as_ref(), &value));
+        }
+
+        if let Some(limit) = self.limits.usable_env_size() {
+            if limit < self.env_size + self.env_pair_len_for(0, 0) {
+                constraints.push(Constraint::EnvSize);
             }
         }
 
-        if self.clear_env {
-            self.env.remove(key.as_ref());
-        } else {
-            self.env.insert(key.as_ref().to_owned(), None);
+        if let Some(limit) = self.limits.env_count {
+            if limit.get() <= self.env_var_count() {
+                constraints.push(Constraint::EnvCount);
+            }
         }
 
-        self
+        if let Some(limit) = self.limits.usable_total_size() {
+            let smallest = self.arg_len_for(0).min(self.env_pair_len_for(0, 0));
+            if limit < self.arg_size + self.env_size + smallest {
+                constraints.push(Constraint::TotalSize);
+            }
+        }
+
+        constraints
     }
 
-    /// Clear all env variables
-    pub fn env_clear(&mut self) -> &mut Self {
-        self.clear_env = true;
-        self.env.clear();
-        self.env_size = 0;
-        self
+    /// Return how many more arguments of `per_arg_len` bytes/characters could be
+    /// added before a limit is reached.
+    ///
+    /// On Windows this assumes nothing in the arguments needs escaping.
+    pub fn arg_capacity_for(&self, per_arg_len: usize) -> usize {
+        self.arg_capacity(self.arg_len_for(per_arg_len))
     }
 
-    // Create a `Command` from this `CommandBuilder`
-    pub fn into_command(&self) -> Command {
-        let mut cmd = Command::new(self.argv.get(0).expect("argv should not be empty"));
-        if self.clear_env {
-            cmd.env_clear();
+    /// Estimate how many commands would be spawned to pass `total_items` arguments
+    /// averaging `avg_item_len` bytes/characters, using this as the base command.
+    ///
+    /// This is only an estimate - real items vary in size, and on Windows may need
+    /// escaping.  If items of this size cannot fit at all, returns 0.
+    pub fn estimate_spawn_count(&self, total_items: usize, avg_item_len: usize) -> usize {
+        match self.arg_capacity_for(avg_item_len) {
+            0 => 0,
+            per_command => total_items.div_ceil(per_command),
         }
+    }
 
-        for (k, v) in &self.env {
-            if let Some(val) = v {
-                cmd.env(k, val);
-            } else {
-                cmd.env_remove(k);
+    /// Return how many commands `batch` would produce for `args`, without creating
+    /// them.
+    ///
+    /// If an argument cannot fit even in an otherwise empty command, its error is
+    /// returned instead, such as `Error::TooLarge`.
+    pub fn count_batches<S: AsRef<OsStr>>(&self, args: &[S]) -> Result<usize> {
+        let mut cmd = self.clone();
+        let mut batches = 0;
+        let mut added = 0;
+
+        for arg in args {
+            if let Err(e) = cmd.arg(arg) {
+                if added == 0 {
+                    return Err(e);
+                }
+                batches += 1;
+                added = 0;
+                cmd.reset_to(self).arg(arg)?;
             }
+            added += 1;
         }
 
-        if self.argv.len() > 1 {
-            cmd.args(&self.argv[1..]);
+        Ok(batches + usize::from(added > 0))
+    }
+
+    /// Check if setting the given environment variable will accomodate our limits.
+    ///
+    /// Return an appropriate `Error` case or `Ok(size)` giving the new env_size.
+    fn check_env(&self, key: &OsStr, value: &OsStr) -> Result<usize> {
+        check_nul(key, Domain::Env)?;
+        check_nul(value, Domain::Env)?;
+
+        let old_value = match self.env.get(&EnvKey::from(key)) {
+            Some(Some(old_value)) => Some(Cow::Borrowed(old_value.as_os_str())),
+            // A removed variable is added back as if new
+            Some(None) => None,
+            None if self.clear_env => None,
+            None => env::var_os(key).map(Cow::Owned),
+        };
+
+        if let Some(old_value) = old_value {
+            // If it was previously set in the command or exists in the inherited env,
+            // do we have space to exchange the old value for the new one?
+            let old_len = self.env_pair_len(key, &old_value);
+            let new_len = self.env_pair_len(key, value);
+            self.check_env_pair_len(new_len)?;
+            if old_len < new_len {
+                self.check_env_size(new_len - old_len)?;
+            }
+            let env_size = match old_value {
+                Cow::Borrowed(_) => release(self.env_size, old_len),
+                Cow::Owned(_) => release_inherited(self.env_size, old_len),
+            };
+            Ok(env_size + new_len)
+        } else {
+            Ok(self.env_size + self.check_env_pair(key, value)?)
         }
+    }
 
-        cmd
+    /// Check whether the given argument would fit, without adding it.
+    ///
+    /// If this succeeds, a following `arg` with the same value is guaranteed to
+    /// succeed provided the command is not modified in between.
+    pub fn would_fit<S: AsRef<OsStr>>(&self, arg: S) -> Result<()> {
+        self.check_arg(arg.as_ref()).map(|_| ())
     }
 
-    /// Return the name of the program that this `CommandBuilder` was constructed with.
-    pub fn get_program(&self) -> &OsStr {
-        &self.argv[0]
+    /// Check whether the given environment variable could be set, without setting it.
+    ///
+    /// If this succeeds, a following `env` with the same key and value is guaranteed
+    /// to succeed provided the command is not modified in between.
+    pub fn would_fit_env<K, V>(&self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.check_env(key.as_ref(), value.as_ref()).map(|_| ())
     }
 
-    /// Return the arguments to be passed to the program
-    pub fn get_args(&self) -> &[OsString] {
-        &self.argv[1..]
+    /// Check whether all of `extra_env` could be set and `extra_args` added to a
+    /// single command, without changing this one.
+    ///
+    /// The environment is applied first, as a later argument may not fit once it has
+    /// been set.  The first error encountered is returned.
+    pub fn plan_fits<S: AsRef<OsStr>>(
+        &self,
+        extra_args: &[S],
+        extra_env: &[(OsString, OsString)],
+    ) -> Result<()> {
+        let mut cmd = self.clone();
+        for (key, value) in extra_env {
+            cmd.env(key, value)?;
+        }
+        cmd.args(extra_args).map(|_| ())
     }
 
-    /// Return the limits set for this `CommandBuilder`.
-    pub fn get_limits(&self) -> CommandLimits {
-        self.limits
+    /// Return the space `arg` would take if added to this command, measured for its
+    /// platform and pointer size.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// let len = cmd.measure_arg("hello");
+    /// let before = cmd.arg_size();
+    /// cmd.arg_with_len("hello".into(), len)?;
+    /// assert_eq!(cmd.arg_size(), before + len);
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn measure_arg<S: AsRef<OsStr>>(&self, arg: S) -> usize {
+        self.arg_len(arg.as_ref())
     }
 
-    /// Return the current space used by arguments.
-    pub fn arg_size(&self) -> usize {
-        self.arg_size
+    /// Add the given argument to the command list if it fits, trusting
+    /// `precomputed_len` as the space it takes rather than measuring it.
+    ///
+    /// This is for tight loops where the length is already known, such as for
+    /// fixed-width items.  It must be the full amount this builder accounts for the
+    /// argument, as given by `measure_arg`, which depends on the builder's platform
+    /// and `CommandLimits::pointer_size`.
+    ///
+    /// **An incorrect `precomputed_len` defeats the limits**, and may lead to
+    /// creating a command too large to spawn.  Debug builds assert that it matches.
+    pub fn arg_with_len(&mut self, arg: OsString, precomputed_len: usize) -> Result<&mut Self> {
+        debug_assert_eq!(precomputed_len, self.arg_len(&arg));
+
+        check_nul(&arg, Domain::Arg)?;
+        self.arg_size += self.check_arg_len(precomputed_len)?;
+        self.argv.push(arg);
+        Ok(self)
     }
 
-    /// Return the current space used by the environment.
-    pub fn env_size(&self) -> usize {
-        self.env_size
+    /// Add the given argument to the command list if it fits.
+    pub fn arg<S>(&mut self, arg: S) -> Result<&mut Self>
+    where
+        S: AsRef<OsStr>,
+    {
+        self.arg_size += self.check_arg(arg.as_ref())?;
+        self.argv.push(arg.as_ref().to_owned());
+        Ok(self)
     }
-}
 
-impl From<&CommandBuilder> for Command {
-    fn from(builder: &CommandBuilder) -> Command {
-        builder.into_command()
+    /// Add the given argument if it fits, taking ownership of it.
+    ///
+    /// If it doesn't fit, `arg` is returned along with the error, so it can be
+    /// retried in another command without keeping a copy.
+    pub fn try_push_arg(&mut self, arg: OsString) -> std::result::Result<(), (OsString, Error)> {
+        match self.check_arg(&arg) {
+            Ok(len) => {
+                self.arg_size += len;
+                self.argv.push(arg);
+                Ok(())
+            }
+            Err(e) => Err((arg, e)),
+        }
+    }
+
+    /// Add the given argument if it isn't already present and it fits.
+    ///
+    /// Returns `Ok(false)` without adding it if an argument equal to `arg` has
+    /// already been added, not counting the program or any placeholder.  The first
+    /// call indexes the existing arguments so later calls are fast, at the cost of
+    /// keeping a copy of each.
+    pub fn arg_unique<S>(&mut self, arg: S) -> Result<bool>
+    where
+        S: AsRef<OsStr>,
+    {
+        let arg = arg.as_ref();
+
+        if self.argv.len() < self.seen_len {
+            self.forget_seen_args();
+        }
+
+        let start = self.seen_len.max(1);
+        let placeholder = self.placeholder.map(|(index, _)| index);
+        for (index, seen) in (start..).zip(self.argv.range(start..self.argv.len())) {
+            if Some(index) != placeholder {
+                self.seen_args.insert(seen.clone());
+            }
+        }
+        self.seen_len = self.argv.len();
+
+        if self.seen_args.contains(arg) {
+            return Ok(false);
+        }
+
+        self.arg(arg)?;
+        self.seen_args.insert(arg.to_owned());
+        self.seen_len = self.argv.len();
+        Ok(true)
+    }
+
+    // Drop the index of arguments, for when they're modified other than by adding.
+    fn forget_seen_args(&mut self) {
+        self.seen_args.clear();
+        self.seen_len = 0;
+    }
+
+    /// Set the working directory for the program.
+    ///
+    /// This does not count against any limits.
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.current_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Add an argument given as raw bytes, if it fits.
+    ///
+    /// On Unix the bytes are used as-is.  Elsewhere they are decoded as UTF-8, with
+    /// invalid sequences replaced by U+FFFD.
+    pub fn arg_bytes(&mut self, bytes: &[u8]) -> Result<&mut Self> {
+        self.arg(bytes_to_os(bytes))
+    }
+
+    /// Add the provided list of arguments to the command if they all fit.
+    ///
+    /// If the entire list does not fit, no arguments are added.
+    pub fn args<S>(&mut self, args: &[S]) -> Result<&mut Self>
+    where
+        S: AsRef<OsStr>,
+    {
+        self.arg_size += self.check_args(args.iter().map(AsRef::as_ref))?;
+        self.argv
+            .extend(args.iter().map(|arg| arg.as_ref().to_owned()));
+        Ok(self)
+    }
+
+    /// Append the arguments of `other`, not including its program, if they all fit.
+    ///
+    /// If the entire list does not fit, no arguments are added.
+    pub fn extend_args_from(&mut self, other: &CommandBuilder) -> Result<&mut Self> {
+        self.arg_size += self.check_args(other.command_args().map(OsString::as_os_str))?;
+        self.argv.extend(other.command_args().cloned());
+        Ok(self)
+    }
+
+    /// Add as many leading arguments from `args` as fit, stopping at the first which
+    /// does not.
+    ///
+    /// Returns the number of arguments added, which is the index of the first one
+    /// that wasn't, along with the error which stopped it.  An argument which is
+    /// `Error::TooLarge` is reported like any other rather than being skipped.
+    pub fn fill_args<S: AsRef<OsStr>>(&mut self, args: &[S]) -> (usize, Option<Error>) {
+        for (i, arg) in args.iter().enumerate() {
+            if let Err(e) = self.arg(arg) {
+                return (i, Some(e));
+            }
+        }
+
+        (args.len(), None)
+    }
+
+    /// Substitute arguments added after this call for the first existing argument
+    /// equal to `token`, rather than appending them, as with `find -exec cmd {} +`.
+    ///
+    /// This should be called once all the fixed arguments have been added.  The
+    /// placeholder itself is not passed to the program, nor returned by `get_args`,
+    /// and does not count against `arg_size`, `arg_count` or `individual_arg_size`.
+    ///
+    /// If no argument matches `token`, or a placeholder is already set, this has no
+    /// effect.
+    pub fn placeholder<S: AsRef<OsStr>>(&mut self, token: S) -> &mut Self {
+        if self.placeholder.is_some() {
+            return self;
+        }
+
+        if let Some(index) = self
+            .argv
+            .iter()
+            .skip(1)
+            .position(|arg| arg == token.as_ref())
+        {
+            let index = index + 1;
+            self.arg_size -= self.arg_len(&self.argv[index]);
+            self.placeholder = Some((index, self.argv.len()));
+            self.forget_seen_args();
+        }
+
+        self
+    }
+
+    /// Append `bytes` to the last argument, if the result will fit.
+    ///
+    /// This allows an argument to be built up incrementally without removing and
+    /// re-adding it.  On Unix the bytes are appended as-is, elsewhere they are
+    /// decoded as UTF-8, lossily, so multi-byte characters should not be split across
+    /// calls.
+    ///
+    /// Returns `Error::NoArgument` if no argument has been added after the program,
+    /// or after the base command when using a `placeholder`.
+    pub fn append_to_last_arg(&mut self, bytes: &[u8]) -> Result<&mut Self> {
+        if bytes.contains(&0) {
+            return Err(Error::ContainsNul {
+                domain: Domain::Arg,
+            });
+        }
+
+        // Neither the program nor the placeholder are ours to extend
+        let first_added = self.placeholder.map_or(1, |(_, base_len)| base_len);
+        if self.argv.len() <= first_added {
+            return Err(Error::NoArgument);
+        }
+
+        let last = self.argv.last().expect("argv should not be empty");
+        let old_len = self.arg_len(last);
+
+        let mut arg = last.clone();
+        arg.push(bytes_to_os(bytes));
+        let new_len = self.arg_len(&arg);
+
+        if self
+            .limits
+            .individual_arg_size
+            .unwrap_or(self.limits.arg_size)
+            .get()
+            < new_len
+        {
+            return Err(Error::TooLarge);
+        }
+
+        let delta = new_len.saturating_sub(old_len);
+        self.check_arg_space(delta)?;
+        self.arg_size += delta;
+        *self.argv.last_mut().expect("argv should not be empty") = arg;
+        self.forget_seen_args();
+        Ok(self)
+    }
+
+    /// Add the arguments in `template`, with every occurrence of `token` replaced by
+    /// `item`, if they all fit.
+    ///
+    /// As with `args`, if the entire list does not fit, no arguments are added.
+    pub fn args_substituted<S, T, I>(
+        &mut self,
+        template: &[S],
+        token: T,
+        item: I,
+    ) -> Result<&mut Self>
+    where
+        S: AsRef<OsStr>,
+        T: AsRef<OsStr>,
+        I: AsRef<OsStr>,
+    {
+        self.args(&substitute_args(template, token.as_ref(), item.as_ref()))
+    }
+
+    /// Set the given environment variable, if it will fit.
+    ///
+    /// On Windows, names which differ only in case refer to the same variable, which
+    /// keeps the name it was first given.
+    pub fn env<K, V>(&mut self, key: K, value: V) -> Result<&mut Self>
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        // Setting the same value again changes nothing
+        let env_key = EnvKey::from(key.as_ref());
+        if let Some(Some(old_value)) = self.env.get(&env_key) {
+            if old_value == value.as_ref() {
+                return Ok(self);
+            }
+        }
+
+        self.env_size = self.check_env(key.as_ref(), value.as_ref())?;
+        self.env_mut()
+            .insert(env_key, Some(value.as_ref().to_owned()));
+
+        Ok(self)
+    }
+
+    /// Set each of the given env variables if they all fit.
+    ///
+    /// Variables are set in order, so a key given more than once takes its last
+    /// value.  If any does not fit, the environment is left unchanged.
+    pub fn envs<I, K, V>(&mut self, vars: I) -> Result<&mut Self>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        let old_env_size = self.env_size;
+        let mut undo = vec![];
+
+        for (key, value) in vars {
+            let env_key = EnvKey::from(key.as_ref());
+            let old_value = self.env.get(&env_key).cloned();
+            if let Err(e) = self.env(&key, value) {
+                for (key, old_value) in undo.into_iter().rev() {
+                    match old_value {
+                        Some(old_value) => self.env_mut().insert(key, old_value),
+                        None => self.env_mut().remove(&key),
+                    };
+                }
+                self.env_size = old_env_size;
+                return Err(e);
+            }
+            undo.push((env_key, old_value));
+        }
+
+        Ok(self)
+    }
+
+    /// Set the given env variable if `value` is `Some`, as with `env`, or remove it
+    /// if `None`, as with `env_remove`.
+    pub fn env_opt<K, V>(&mut self, key: K, value: Option<V>) -> Result<&mut Self>
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        match value {
+            Some(value) => self.env(key, value),
+            None => Ok(self.env_remove(key)),
+        }
+    }
+
+    /// Remove the given env variable
+    ///
+    /// This ensures the variable is not set even if it's added to the global environment later.
+    ///
+    /// If the environment is not inherited, only variables set on this command exist,
+    /// so removing anything else has no effect.
+    pub fn env_remove<K>(&mut self, key: K) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+    {
+        self.try_env_remove(key);
+        self
+    }
+
+    /// Remove the given env variable as with `env_remove`, returning the space
+    /// freed.
+    ///
+    /// This is 0 if the variable was not set, such as if it was already removed.
+    pub fn try_env_remove<K>(&mut self, key: K) -> usize
+    where
+        K: AsRef<OsStr>,
+    {
+        let key = key.as_ref();
+
+        let env_key = EnvKey::from(key);
+
+        if self.clear_env {
+            // The map may be shared with clones, so only copy it to make a change
+            if !self.env.contains_key(&env_key) {
+                return 0;
+            }
+            return match self.env_mut().remove(&env_key) {
+                Some(Some(value)) => self.release_env(self.env_pair_len(key, &value)),
+                _ => 0,
+            };
+        }
+
+        match self.env.get(&env_key) {
+            Some(Some(value)) => {
+                let freed = self.env_pair_len(key, value);
+                self.env_mut().insert(env_key, None);
+                self.release_env(freed)
+            }
+            // If it's already been set to None, do nothing instead of reinserting
+            Some(None) => 0,
+            None => {
+                let freed = env::var_os(key)
+                    .map(|value| self.env_pair_len(key, &value))
+                    .unwrap_or(0);
+                self.env_mut().insert(env_key, None);
+                self.env_size = release_inherited(self.env_size, freed);
+                freed
+            }
+        }
+    }
+
+    // The variables set on this command, copied first if shared with a clone.
+    fn env_mut(&mut self) -> &mut EnvMap {
+        Arc::make_mut(&mut self.env)
+    }
+
+    // Release `len` from env_size, returning it.
+    fn release_env(&mut self, len: usize) -> usize {
+        self.env_size = release(self.env_size, len);
+        len
+    }
+
+    /// Drop any environment changes which have no effect on the inherited
+    /// environment, such as removing variables which are not set, or setting them to
+    /// the value they already have.
+    ///
+    /// This frees space counted against `env_count`, but means these variables will
+    /// follow future changes to the process environment.  It has no effect if the
+    /// environment is not inherited.
+    pub fn coalesce_env(&mut self) -> &mut Self {
+        if !self.clear_env {
+            self.env_mut()
+                .retain(|k, v| env::var_os(k).as_deref() != v.as_deref());
+        }
+
+        self
+    }
+
+    /// Clear all env variables
+    ///
+    /// Returns `Error::EnvFrozen` if the environment has been frozen with
+    /// `freeze_env`.
+    pub fn env_clear(&mut self) -> Result<&mut Self> {
+        self.check_env_frozen()?;
+        self.clear_env = true;
+        self.clear_env_map();
+        self.env_size = 0;
+        Ok(self)
+    }
+
+    // Empty the map of variables set or removed, keeping its allocation if it isn't
+    // shared.
+    fn clear_env_map(&mut self) {
+        match Arc::get_mut(&mut self.env) {
+            Some(env) => env.clear(),
+            None => self.env = Arc::new(EnvMap::with_capacity(env_capacity(&self.limits))),
+        }
+    }
+
+    /// Return how many more environment variables with keys of `key_len` and values
+    /// of `value_len` bytes/characters could be added before a limit is reached.
+    pub fn max_additional_env_of_len(&self, key_len: usize, value_len: usize) -> usize {
+        let len = self.env_pair_len_for(key_len, value_len);
+
+        if self
+            .limits
+            .individual_env_size
+            .or(self.limits.env_size)
+            .unwrap_or(self.limits.arg_size)
+            .get()
+            < len
+        {
+            return 0;
+        }
+
+        let by_count = self
+            .limits
+            .env_count
+            .map(|limit| limit.get().saturating_sub(self.env_var_count()))
+            .unwrap_or(usize::MAX);
+
+        (self.remaining_env_space() / len).min(by_count)
+    }
+
+    /// Check that the program, arguments and environment can all be passed to a new
+    /// process without loss.
+    ///
+    /// Values with interior NULs are already rejected as they're added, so this
+    /// only rejects values on Windows which do not round-trip through UTF-16.
+    pub fn validate_encoding(&self) -> Result<()> {
+        let env = self
+            .env
+            .iter()
+            .flat_map(|(k, v)| std::iter::once(&**k).chain(v.as_deref()));
+
+        if self
+            .argv
+            .iter()
+            .map(OsString::as_os_str)
+            .chain(env)
+            .all(is_valid_encoding)
+        {
+            Ok(())
+        } else {
+            Err(Error::InvalidEncoding)
+        }
+    }
+
+    /// Make this builder a copy of `base`, reusing existing allocations where
+    /// possible.
+    ///
+    /// Like a clone, this shares the arguments and environment of `base` rather than
+    /// copying them, but also keeps the space this builder had for arguments added
+    /// to it, so is the cheapest way to start each batch from a base command.
+    pub fn reset_to(&mut self, base: &CommandBuilder) -> &mut Self {
+        self.limits = base.limits;
+        self.platform = base.platform;
+        self.argv.clone_from(&base.argv);
+        self.env.clone_from(&base.env);
+        self.arg_size = base.arg_size;
+        self.env_size = base.env_size;
+        self.clear_env = base.clear_env;
+        self.env_frozen = base.env_frozen;
+        self.placeholder = base.placeholder;
+        self.current_dir.clone_from(&base.current_dir);
+        self.wrapper_size = base.wrapper_size;
+        self.seen_args.clone_from(&base.seen_args);
+        self.seen_len = base.seen_len;
+        self
+    }
+
+    /// Create a `Command` from this `CommandBuilder`
+    ///
+    /// # Panics
+    ///
+    /// If there is no program to run, which cannot happen for a builder created with
+    /// any of the public constructors.
+    pub fn into_command(&self) -> Command {
+        self.try_into_command().expect("argv should not be empty")
+    }
+
+    /// Create a `Command` from this `CommandBuilder`, or return `Error::EmptyCommand`
+    /// if there is no program to run.
+    pub fn try_into_command(&self) -> Result<Command> {
+        self.assemble()
+    }
+
+    /// Assemble the program, arguments, environment and working directory into a
+    /// new command of any supported type.
+    fn assemble<C: CommandSink>(&self) -> Result<C> {
+        self.assemble_with_args(self.command_args())
+    }
+
+    /// As `assemble`, but passing `args` instead of this command's arguments.
+    fn assemble_with_args<C, I, S>(&self, args: I) -> Result<C>
+    where
+        C: CommandSink,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut cmd = C::new(self.argv.first().ok_or(Error::EmptyCommand)?);
+        if self.clear_env {
+            cmd.env_clear();
+        }
+
+        for (k, v) in self.env.iter() {
+            if let Some(val) = v {
+                cmd.env(k, val);
+            } else {
+                cmd.env_remove(k);
+            }
+        }
+
+        for arg in args {
+            cmd.arg(arg.as_ref());
+        }
+        if let Some(dir) = &self.current_dir {
+            cmd.current_dir(dir);
+        }
+        Ok(cmd)
+    }
+
+    /// Create a `Command` from this `CommandBuilder`, moving its arguments to a
+    /// response file in `dir` passed as `@file` if the command would not fit within
+    /// the limits of this system.
+    ///
+    /// The command is measured as it would be spawned, including the inherited
+    /// environment, against `CommandLimits::detect()` rather than this builder's own
+    /// limits, so this is for builders given limits larger than the system allows.
+    /// Arguments are quoted as for `to_command_line`, separated by newlines, and
+    /// written as UTF-8, or raw bytes on Unix.
+    ///
+    /// The `@file` syntax is a convention of programs such as compilers and linkers,
+    /// not of the operating system, so this must only be used to run programs which
+    /// understand it, on any platform.
+    ///
+    /// The `ResponseFile` is removed when dropped, so it must be kept until the
+    /// program has finished with it.
+    pub fn into_command_with_response_file(
+        &self,
+        dir: &Path,
+    ) -> io::Result<(Command, Option<ResponseFile>)> {
+        let argv: Vec<OsString> = self.argv().map(OsStr::to_owned).collect();
+        let env: Vec<(OsString, OsString)> = self
+            .effective_env()
+            .into_iter()
+            .map(|(key, val)| (key.into_os_string(), val))
+            .collect();
+
+        match check_plan(&CommandLimits::detect(), &argv, &env) {
+            Err(Error::TooLarge | Error::TooMany { .. } | Error::InsufficientSpace { .. }) => (),
+            _ => return Ok((self.into_command(), None)),
+        }
+
+        let mut contents = OsString::new();
+        for arg in self.command_args() {
+            push_quoted_arg(&mut contents, arg);
+            contents.push("\n");
+        }
+
+        let response = ResponseFile::create(dir, &contents)?;
+        let mut arg = OsString::from("@");
+        arg.push(response.path());
+
+        let cmd = self
+            .assemble_with_args([arg])
+            .expect("argv should not be empty");
+        Ok((cmd, Some(response)))
+    }
+
+    /// Return the arguments in the order they're passed to the program, with any
+    /// placeholder replaced by those added after it.
+    fn command_args(&self) -> ArgsIter<'_> {
+        let len = self.argv.len();
+        let (before, added, after) = match self.placeholder {
+            Some((index, base_len)) => (1..index, base_len..len, index + 1..base_len),
+            None => (len.min(1)..len, 0..0, 0..0),
+        };
+
+        self.argv
+            .range(before)
+            .chain(self.argv.range(added))
+            .chain(self.argv.range(after))
+    }
+
+    /// Render the command line as it would be run, with each argument quoted for
+    /// the current platform: for a POSIX shell on Unix, and for
+    /// `CommandLineToArgvW` on Windows.
+    ///
+    /// This is intended for logging, such as with `xargs -t`.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// cmd.arg("hello world")?;
+    /// # #[cfg(unix)]
+    /// assert_eq!(cmd.to_command_line(), "echo 'hello world'");
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn to_command_line(&self) -> OsString {
+        let mut line = OsString::new();
+        push_quoted_arg(&mut line, self.get_program());
+
+        for arg in self.command_args() {
+            line.push(" ");
+            push_quoted_arg(&mut line, arg);
+        }
+
+        line
+    }
+
+    /// Render the command line as `to_command_line`, preceded by the environment
+    /// changes made on this command, as a line for a POSIX shell.
+    ///
+    /// Variables set are given as `KEY=value` assignments.  If the environment is
+    /// cleared, or variables are removed, the line is run through `env`, with `-i`
+    /// or `-u KEY` respectively.  Arguments are quoted for the current platform, so
+    /// the result is only usable by a shell on Unix.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// cmd.env("TZ", "Europe/London")?.env("GREETING", "hello world")?;
+    /// cmd.arg("$GREETING")?;
+    /// # #[cfg(unix)]
+    /// assert_eq!(
+    ///     cmd.to_shell_command(),
+    ///     "GREETING='hello world' TZ=Europe/London echo '$GREETING'"
+    /// );
+    ///
+    /// cmd.env_remove("LANG");
+    /// # #[cfg(unix)]
+    /// assert_eq!(
+    ///     cmd.to_shell_command(),
+    ///     "env -u LANG 'GREETING=hello world' TZ=Europe/London echo '$GREETING'"
+    /// );
+    ///
+    /// cmd.env_clear()?.env("TZ", "UTC")?;
+    /// # #[cfg(unix)]
+    /// assert_eq!(cmd.to_shell_command(), "env -i TZ=UTC echo '$GREETING'");
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn to_shell_command(&self) -> OsString {
+        let is_name = |key: &OsStr| {
+            key.to_str().is_some_and(|key| {
+                !key.starts_with(|c: char| c.is_ascii_digit())
+                    && !key.is_empty()
+                    && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            })
+        };
+
+        let envs: Vec<_> = self
+            .get_envs()
+            .filter(|(_, value)| !self.clear_env || value.is_some())
+            .collect();
+
+        // Assignments must be plain names for the shell to recognise them, so
+        // anything else needs to go through env, as do removals.
+        let use_env = self.clear_env
+            || envs
+                .iter()
+                .any(|&(key, value)| value.is_none() || !is_name(key));
+
+        let mut line = OsString::new();
+        if use_env {
+            line.push("env ");
+            if self.clear_env {
+                line.push("-i ");
+            }
+        }
+
+        // env takes options before any assignments
+        for &(key, _) in envs.iter().filter(|(_, value)| value.is_none()) {
+            line.push("-u ");
+            push_quoted_arg(&mut line, key);
+            line.push(" ");
+        }
+
+        for &(key, value) in &envs {
+            if let Some(value) = value {
+                if use_env {
+                    let mut pair = key.to_owned();
+                    pair.push("=");
+                    pair.push(value);
+                    push_quoted_arg(&mut line, &pair);
+                } else {
+                    line.push(key);
+                    line.push("=");
+                    push_quoted_arg(&mut line, value);
+                }
+                line.push(" ");
+            }
+        }
+
+        line.push(self.to_command_line());
+        line
+    }
+
+    /// Create a `Command` from this `CommandBuilder`, consuming it, or return
+    /// `Error::EmptyCommand` if there is no program to run.
+    ///
+    /// The arguments and environment are moved rather than borrowed, for when the
+    /// builder is not needed afterwards, such as for the final batch.
+    pub fn into_command_owned(self) -> Result<Command> {
+        let mut argv = self.argv.into_vec();
+
+        if let Some((index, base_len)) = self.placeholder {
+            // Drop the placeholder and move the arguments added since into its place
+            argv.remove(index);
+            argv[index..].rotate_left(base_len - 1 - index);
+        }
+
+        let mut argv = argv.into_iter();
+        let mut cmd = Command::new(argv.next().ok_or(Error::EmptyCommand)?);
+        if self.clear_env {
+            cmd.env_clear();
+        }
+
+        for (k, v) in Arc::unwrap_or_clone(self.env) {
+            if let Some(val) = v {
+                cmd.env(k, val);
+            } else {
+                cmd.env_remove(k);
+            }
+        }
+
+        cmd.args(argv);
+        if let Some(dir) = self.current_dir {
+            cmd.current_dir(dir);
+        }
+        Ok(cmd)
+    }
+
+    /// Create a `tokio::process::Command` from this `CommandBuilder`
+    #[cfg(feature = "tokio")]
+    pub fn into_tokio_command(&self) -> tokio::process::Command {
+        self.assemble().expect("argv should not be empty")
+    }
+
+    /// Create an `async_process::Command` from this `CommandBuilder`
+    #[cfg(feature = "async-process")]
+    pub fn into_async_command(&self) -> async_process::Command {
+        self.assemble().expect("argv should not be empty")
+    }
+
+    /// Spawn the command, splitting the arguments in half and retrying each half if
+    /// the platform rejects it as too long, and so on recursively.
+    ///
+    /// Every argument after the program is assumed to be an independent item which
+    /// may be passed to a separate invocation, as with `xargs`.
+    ///
+    /// On error, any children which were already spawned are left running.
+    pub fn spawn_with_e2big_retry(&self) -> io::Result<Vec<Child>> {
+        self.spawn_with_e2big_retry_using(Command::spawn)
+    }
+
+    /// As `spawn_with_e2big_retry`, but with a custom function for spawning each
+    /// `Command`.
+    pub fn spawn_with_e2big_retry_using<T, F>(&self, mut spawn: F) -> io::Result<Vec<T>>
+    where
+        F: FnMut(&mut Command) -> io::Result<T>,
+    {
+        let mut spawned = vec![];
+        self.spawn_split(&mut spawn, &mut spawned)?;
+        Ok(spawned)
+    }
+
+    fn spawn_split<T, F>(&self, spawn: &mut F, spawned: &mut Vec<T>) -> io::Result<()>
+    where
+        F: FnMut(&mut Command) -> io::Result<T>,
+    {
+        let base_len = self.base_len();
+
+        match spawn(&mut self.into_command()) {
+            Ok(child) => {
+                spawned.push(child);
+                Ok(())
+            }
+            Err(e) if is_arg_list_too_long(&e) && self.argv.len() > base_len + 1 => {
+                let mid = base_len + (self.argv.len() - base_len) / 2;
+                self.split_args(base_len..mid).spawn_split(spawn, spawned)?;
+                self.split_args(mid..self.argv.len())
+                    .spawn_split(spawn, spawned)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Return a copy of this `CommandBuilder` with only the given range of arguments
+    /// following the program, or the base arguments if a placeholder is set.
+    fn split_args(&self, range: Range<usize>) -> Self {
+        let mut cmd = self.clone();
+        cmd.argv.truncate(self.base_len());
+        cmd.argv.extend(self.argv.range(range).cloned());
+        cmd.arg_size = cmd.measure_args();
+        cmd.forget_seen_args();
+        cmd
+    }
+
+    /// Return the number of arguments which are always passed, if a placeholder is
+    /// set, or otherwise just the program.
+    fn base_len(&self) -> usize {
+        self.placeholder.map(|(_, base_len)| base_len).unwrap_or(1)
+    }
+
+    /// Return the space taken by the current arguments, excluding any placeholder,
+    /// and including any reserved for a wrapper.
+    fn measure_args(&self) -> usize {
+        let total: usize =
+            self.argv.iter().map(|arg| self.arg_len(arg)).sum::<usize>() + self.wrapper_size;
+
+        match self.placeholder {
+            Some((index, _)) => total - self.arg_len(&self.argv[index]),
+            None => total,
+        }
+    }
+
+    /// Resolve the program to an absolute path, searching the `PATH` the command will
+    /// be spawned with rather than that of the current process.
+    ///
+    /// Programs containing a path separator are not searched for, and are only made
+    /// absolute.  These, and any relative directories in `PATH`, are taken relative
+    /// to the `current_dir` of the command if one is set, as the program is run
+    /// from there on Unix, and to the current directory otherwise.
+    pub fn resolve_program(&self) -> io::Result<PathBuf> {
+        let program = Path::new(self.get_program());
+        let mut dir = env::current_dir()?;
+        if let Some(current_dir) = &self.current_dir {
+            dir.push(current_dir);
+        }
+
+        if program.components().count() > 1 || program.is_absolute() {
+            return Ok(dir.join(program));
+        }
+
+        let path = match self.env.get(&EnvKey::from(OsStr::new("PATH"))) {
+            Some(path) => path.clone(),
+            None if self.clear_env => None,
+            None => env::var_os("PATH"),
+        };
+
+        path.iter()
+            .flat_map(env::split_paths)
+            .flat_map(|path_dir| executable_path(&dir.join(path_dir).join(program)))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "program not found in PATH"))
+    }
+
+    /// Return the name of the program that this `CommandBuilder` was constructed with.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// cmd.arg("hello")?.arg("world")?;
+    /// assert_eq!(cmd.get_program(), "echo");
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn get_program(&self) -> &OsStr {
+        &self.argv[0]
+    }
+
+    /// Return the arguments to be passed to the program, in the order `into_command`
+    /// passes them, as with `std::process::Command::get_args`.
+    ///
+    /// If a `placeholder` is set, the arguments added after it are given in its
+    /// place, and the placeholder itself is not included.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// cmd.arg("hello")?.arg("world")?;
+    /// assert!(cmd.get_args().eq(["hello", "world"]));
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn get_args(&self) -> impl Iterator<Item = &OsStr> {
+        self.command_args().map(OsString::as_os_str)
+    }
+
+    /// Return the program followed by its arguments, in the order `into_command`
+    /// passes them, as with iterating over `&CommandBuilder`.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("find")?;
+    /// cmd.args(&["-exec", "ls", "{}", "+"])?.placeholder("{}");
+    /// cmd.arg("a")?.arg("b")?;
+    /// assert!(cmd.argv().eq(["find", "-exec", "ls", "a", "b", "+"]));
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn argv(&self) -> ArgvIter<'_> {
+        self.into_iter()
+    }
+
+    /// Return the working directory set with `current_dir`, if any.
+    pub fn get_current_dir(&self) -> Option<&Path> {
+        self.current_dir.as_deref()
+    }
+
+    /// Return the environment variables explicitly set or removed for the program,
+    /// in key order, as with `std::process::Command::get_envs`.
+    ///
+    /// A value of `None` means the variable has been removed.  Inherited variables
+    /// are not included unless captured with `capture_env`.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// # use std::ffi::OsStr;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// cmd.env("TZ", "UTC")?.env_remove("LANG");
+    /// let envs: Vec<_> = cmd.get_envs().collect();
+    /// assert_eq!(envs, [(OsStr::new("LANG"), None), (OsStr::new("TZ"), Some(OsStr::new("UTC")))]);
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn get_envs(&self) -> impl Iterator<Item = (&OsStr, Option<&OsStr>)> {
+        let mut envs: Vec<_> = self.env.iter().map(|(k, v)| (&**k, v.as_deref())).collect();
+        envs.sort_unstable_by_key(|&(k, _)| k);
+        envs.into_iter()
+    }
+
+    /// Return the value `key` will have in the program's environment, whether set
+    /// on this command or inherited.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// cmd.env("TZ", "UTC")?;
+    /// assert_eq!(cmd.get_env("TZ").as_deref(), Some("UTC".as_ref()));
+    ///
+    /// cmd.env_clear()?;
+    /// assert_eq!(cmd.get_env("TZ"), None);
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn get_env<K: AsRef<OsStr>>(&self, key: K) -> Option<Cow<'_, OsStr>> {
+        let key = key.as_ref();
+
+        match self.env.get(&EnvKey::from(key)) {
+            Some(value) => value.as_deref().map(Cow::Borrowed),
+            None if self.clear_env => None,
+            None => env::var_os(key).map(Cow::Owned),
+        }
+    }
+
+    /// Return the limits set for this `CommandBuilder`.
+    ///
+    /// ```
+    /// # use command_limits::{CommandBuilder, CommandLimits};
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// cmd.arg("hello")?.arg("world")?;
+    /// assert_eq!(cmd.get_limits().arg_size, CommandLimits::default().arg_size);
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn get_limits(&self) -> CommandLimits {
+        self.limits
+    }
+
+    /// Return the current space used by arguments.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// let base = cmd.arg_size();
+    /// cmd.arg("hello")?.arg("world")?;
+    /// assert!(cmd.arg_size() > base);
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn arg_size(&self) -> usize {
+        self.arg_size
+    }
+
+    /// Return the size of the arguments themselves, with their separators or
+    /// terminators but not the pointers to each which `arg_size` includes on Unix.
+    ///
+    /// This is the length of the command line as a string, for display or for
+    /// limits which don't include the pointer array.
+    pub fn raw_arg_bytes(&self) -> usize {
+        let pointer_len = self.platform.pointer_len(self.limits.pointer_size);
+
+        std::iter::once(&self.argv[0])
+            .chain(self.command_args())
+            .map(|arg| self.arg_len(arg) - pointer_len)
+            .sum()
+    }
+
+    /// Return the current space used by the environment.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// cmd.env_clear()?.arg("hello")?.arg("world")?;
+    /// assert_eq!(cmd.env_size(), 0);
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn env_size(&self) -> usize {
+        self.env_size
+    }
+
+    /// Return the program and each argument, in the order they're passed, with the
+    /// space each takes.
+    ///
+    /// These sum to `arg_size`, less any space set aside with `reserve_for_wrapper`.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new("echo")?;
+    /// cmd.arg("hello")?.arg("world")?;
+    /// let total: usize = cmd.arg_sizes().map(|(_, len)| len).sum();
+    /// assert_eq!(total, cmd.arg_size());
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn arg_sizes(&self) -> impl Iterator<Item = (&OsStr, usize)> {
+        self.into_iter().map(|arg| (arg, self.arg_len(arg)))
+    }
+
+    /// Return the name of each variable in the environment the program will be
+    /// spawned with, sorted, with the space each takes.
+    ///
+    /// These sum to `env_size`.  Names are owned, since inherited variables are
+    /// read from the current environment.
+    pub fn env_sizes(&self) -> impl Iterator<Item = (OsString, usize)> + '_ {
+        self.effective_env().into_iter().map(|(k, v)| {
+            let len = self.env_pair_len(&k, &v);
+            (k.into_os_string(), len)
+        })
+    }
+
+    /// Return the number of variables in the environment the program will be
+    /// spawned with: those inherited, unless cleared, and those set, less any
+    /// removed.
+    ///
+    /// Removals are kept on the builder to override inherited variables, so this
+    /// may differ from the count of `get_envs`.
+    ///
+    /// ```
+    /// # use command_limits::CommandBuilder;
+    /// let mut cmd = CommandBuilder::new_empty_env("env")?;
+    /// cmd.env("A", "1")?.env("B", "2")?;
+    /// cmd.env_remove("A");
+    /// assert_eq!(cmd.effective_env_count(), 1);
+    /// # Ok::<(), command_limits::Error>(())
+    /// ```
+    pub fn effective_env_count(&self) -> usize {
+        self.effective_env().len()
+    }
+
+    /// Return the estimated space the command will take when spawned, as the sum of
+    /// `arg_size` and `env_size`.
+    ///
+    /// This is what is compared against `CommandLimits::arg_size` when the limits
+    /// don't set a separate `env_size`.  To check the estimate against a real
+    /// system, find the largest command which spawns without `E2BIG` and compare its
+    /// estimate with the limit; the test built with the `calibrate` feature does
+    /// this on Unix.
+    pub fn estimated_total_bytes(&self) -> usize {
+        self.arg_size + self.env_size
+    }
+
+    /// Return a snapshot of how much of its limits this command is using.
+    pub fn utilization(&self) -> Utilization {
+        let arg_bytes_total = self.limits.usable_arg_size();
+
+        Utilization {
+            arg_bytes_used: self.arg_size,
+            arg_bytes_total,
+            env_bytes_used: self.env_size,
+            env_bytes_total: self.limits.usable_env_size().unwrap_or(arg_bytes_total),
+            arg_count_used: self.arg_count(),
+            arg_count_limit: self.limits.arg_count.map(NonZeroUsize::get),
+            shared_space: self.limits.env_size.is_none(),
+        }
+    }
+
+    /// Return the space remaining for arguments.
+    ///
+    /// If the limits do not set a separate `env_size`, or set a `total_size`, this
+    /// is shared with the environment.
+    pub fn remaining_arg_space(&self) -> usize {
+        let remaining = if self.limits.env_size.is_some() {
+            self.limits.usable_arg_size().saturating_sub(self.arg_size)
+        } else {
+            self.limits
+                .usable_arg_size()
+                .saturating_sub(self.arg_size + self.env_size)
+        };
+
+        remaining.min(self.remaining_total_space())
+    }
+
+    /// Return the space remaining for the environment.
+    ///
+    /// If the limits do not set a separate `env_size`, or set a `total_size`, this
+    /// is shared with the arguments.
+    pub fn remaining_env_space(&self) -> usize {
+        let remaining = if let Some(env_limit) = self.limits.usable_env_size() {
+            env_limit.saturating_sub(self.env_size)
+        } else {
+            self.limits
+                .usable_arg_size()
+                .saturating_sub(self.arg_size + self.env_size)
+        };
+
+        remaining.min(self.remaining_total_space())
+    }
+
+    // The space remaining under total_size, if it's set.
+    fn remaining_total_space(&self) -> usize {
+        self.limits.usable_total_size().map_or(usize::MAX, |total| {
+            total.saturating_sub(self.arg_size + self.env_size)
+        })
+    }
+}
+
+type ArgsIter<'a> = Chain<Chain<ArgvRange<'a>, ArgvRange<'a>>, ArgvRange<'a>>;
+
+/// An iterator over the program and arguments of a `CommandBuilder`, in the order
+/// they're passed to the program.
+///
+/// Created by iterating over `&CommandBuilder`.
+#[derive(Debug, Clone)]
+pub struct ArgvIter<'a> {
+    inner: Chain<ArgvRange<'a>, ArgsIter<'a>>,
+}
+
+impl<'a> Iterator for ArgvIter<'a> {
+    type Item = &'a OsStr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(OsString::as_os_str)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> IntoIterator for &'a CommandBuilder {
+    type Item = &'a OsStr;
+    type IntoIter = ArgvIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ArgvIter {
+            inner: self
+                .argv
+                .range(0..self.argv.len().min(1))
+                .chain(self.command_args()),
+        }
+    }
+}
+
+impl TryFrom<&[OsString]> for CommandBuilder {
+    type Error = Error;
+
+    fn try_from(argv: &[OsString]) -> Result<Self> {
+        Self::from_argv(argv)
+    }
+}
+
+impl From<&CommandBuilder> for Command {
+    fn from(builder: &CommandBuilder) -> Command {
+        builder.into_command()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed(arg_size: usize) -> CommandLimits {
+        CommandLimits::fixed(NonZeroUsize::new(arg_size).unwrap())
+    }
+
+    #[test]
+    fn append_to_last_arg_needs_an_argument() {
+        let mut cmd = CommandBuilder::new_empty_env("echo").unwrap();
+        assert_eq!(
+            cmd.append_to_last_arg(b"xyz").unwrap_err(),
+            Error::NoArgument
+        );
+        assert_eq!(cmd.get_program(), "echo");
+
+        cmd.arg("a").unwrap().arg("{}").unwrap().placeholder("{}");
+        assert_eq!(
+            cmd.append_to_last_arg(b"xyz").unwrap_err(),
+            Error::NoArgument
+        );
+
+        cmd.arg("b").unwrap().append_to_last_arg(b"xyz").unwrap();
+        assert!(cmd.argv().eq(["echo", "a", "bxyz"]));
+        let total: usize = cmd.arg_sizes().map(|(_, len)| len).sum();
+        assert_eq!(total, cmd.arg_size());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn e2big_retry_halves_batches() {
+        let items: Vec<OsString> = (0..10).map(|i| i.to_string().into()).collect();
+        let mut cmd = CommandBuilder::new_empty_env("echo").unwrap();
+        cmd.args(&items).unwrap();
+
+        let batches = cmd
+            .spawn_with_e2big_retry_using(|cmd| {
+                let args: Vec<OsString> = cmd.get_args().map(OsStr::to_owned).collect();
+                if args.len() > 3 {
+                    Err(io::Error::from_raw_os_error(libc::E2BIG))
+                } else {
+                    Ok(args)
+                }
+            })
+            .unwrap();
+
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [2, 3, 2, 3]);
+        assert_eq!(batches.concat(), items);
+    }
+
+    fn separate_pools() -> CommandBuilder {
+        let limits = CommandLimits {
+            env_size: NonZeroUsize::new(100),
+            ..fixed(100)
+        };
+        CommandBuilder::empty_env_with_limits("echo", limits).unwrap()
+    }
+
+    #[test]
+    fn full_arg_pool_leaves_env_pool() {
+        let mut cmd = separate_pools();
+        while cmd.arg("argument").is_ok() {}
+        assert!(matches!(
+            cmd.arg("argument"),
+            Err(Error::InsufficientSpace {
+                domain: Domain::Arg,
+                ..
+            })
+        ));
+        assert!(cmd.env("KEY", "value").is_ok());
+    }
+
+    #[test]
+    fn full_env_pool_leaves_arg_pool() {
+        let mut cmd = separate_pools();
+        let mut i = 0;
+        while cmd.env(format!("KEY{}", i), "value").is_ok() {
+            i += 1;
+        }
+        assert!(matches!(
+            cmd.env("KEY", "value"),
+            Err(Error::InsufficientSpace {
+                domain: Domain::Env,
+                ..
+            })
+        ));
+        assert!(cmd.arg("argument").is_ok());
+    }
+
+    #[test]
+    fn env_clear_respects_freeze() {
+        let mut cmd = CommandBuilder::new_empty_env("echo").unwrap();
+        cmd.env("KEY", "value").unwrap().freeze_env().unwrap();
+        assert_eq!(cmd.env_clear().unwrap_err(), Error::EnvFrozen);
+        assert_eq!(cmd.get_env("KEY").as_deref(), Some(OsStr::new("value")));
+    }
+
+    #[test]
+    fn arg_with_len_uses_builder_measurement() {
+        let limits = CommandLimits {
+            pointer_size: PointerSize::Native,
+            ..fixed(4096)
+        };
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+        cmd.reinterpret_for(limits, Platform::Windows).unwrap();
+
+        let len = cmd.measure_arg("hello world");
+        let before = cmd.arg_size();
+        cmd.arg_with_len("hello world".into(), len).unwrap();
+        assert_eq!(cmd.arg_size(), before + len);
+    }
+
+    #[test]
+    fn placeholder_is_not_counted() {
+        let limits = CommandLimits {
+            arg_count: NonZeroUsize::new(3),
+            individual_arg_size: NonZeroUsize::new(64),
+            ..fixed(4096)
+        };
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+        cmd.arg("{}").unwrap().placeholder("{}");
+        cmd.arg("a").unwrap().arg("b").unwrap();
+        assert!(matches!(cmd.arg("c"), Err(Error::TooMany { limit: 3, .. })));
+        assert_eq!(cmd.utilization().arg_count_used, 3);
+
+        let mut cmd = CommandBuilder::new_empty_env("echo").unwrap();
+        let token = "x".repeat(100);
+        cmd.arg(&token).unwrap().placeholder(&token);
+        cmd.set_max_data_args(NonZeroUsize::new(1)).unwrap();
+        assert_eq!(cmd.get_limits().arg_count, NonZeroUsize::new(2));
+        assert!(cmd.set_individual_arg_size(NonZeroUsize::new(64)).is_ok());
+    }
+
+    #[cfg(feature = "smallvec")]
+    #[test]
+    fn arg_count_keeps_argv_inline() {
+        let limits = CommandLimits {
+            arg_count: NonZeroUsize::new(100),
+            ..fixed(4096)
+        };
+        let cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+        assert!(!cmd.argv.spilled());
+    }
+
+    #[test]
+    fn env_size_returns_to_zero() {
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", fixed(4096)).unwrap();
+        cmd.env("KEY", "value").unwrap();
+        cmd.env("KEY", "a longer value").unwrap();
+        cmd.env("KEY", "v").unwrap();
+        cmd.env_remove("KEY");
+        assert_eq!(cmd.env_size(), 0);
+    }
+
+    #[test]
+    fn env_size_returns_to_inherited() {
+        let _env = lock_env();
+        let mut cmd = CommandBuilder::new("echo").unwrap();
+        let inherited = cmd.env_size();
+        cmd.env("COMMAND_LIMITS_TEST_SET", "value").unwrap();
+        cmd.env("COMMAND_LIMITS_TEST_SET", "a longer value")
+            .unwrap();
+        cmd.env_remove("COMMAND_LIMITS_TEST_SET");
+        assert_eq!(cmd.env_size(), inherited);
+    }
+
+    #[test]
+    fn set_limits_keeps_env_size() {
+        let _env = lock_env();
+        let mut cmd = CommandBuilder::new("echo").unwrap();
+        let env_size = cmd.env_size();
+        env::set_var("COMMAND_LIMITS_TEST_SETTER", "x".repeat(10000));
+        let res = cmd.set_arg_count(None).map(|cmd| cmd.env_size());
+        env::remove_var("COMMAND_LIMITS_TEST_SETTER");
+        assert_eq!(res, Ok(env_size));
+    }
+
+    #[test]
+    fn removing_grown_inherited_variable_saturates() {
+        let _env = lock_env();
+        let mut cmd = CommandBuilder::new("echo").unwrap();
+        let inherited = cmd.env_size();
+        env::set_var("COMMAND_LIMITS_TEST_LATE", "x".repeat(10000));
+        cmd.env_remove("COMMAND_LIMITS_TEST_LATE");
+        env::remove_var("COMMAND_LIMITS_TEST_LATE");
+        assert!(cmd.env_size() < inherited);
+    }
+
+    fn pair_len(key: &str, value: &str) -> usize {
+        sizing::env_pair_len(key.as_ref(), value.as_ref())
+    }
+
+    #[test]
+    fn overwriting_inherited_variable_tracks_env_size() {
+        let _env = lock_env();
+        env::set_var("COMMAND_LIMITS_TEST_OVERWRITE", "value");
+        let mut cmd = CommandBuilder::new("echo").unwrap();
+        let inherited = cmd.env_size() - pair_len("COMMAND_LIMITS_TEST_OVERWRITE", "value");
+
+        let longer = cmd
+            .env("COMMAND_LIMITS_TEST_OVERWRITE", "a much longer value")
+            .map(|cmd| cmd.env_size());
+        let shorter = cmd
+            .env("COMMAND_LIMITS_TEST_OVERWRITE", "v")
+            .map(|cmd| cmd.env_size());
+        env::remove_var("COMMAND_LIMITS_TEST_OVERWRITE");
+
+        assert_eq!(
+            longer,
+            Ok(inherited + pair_len("COMMAND_LIMITS_TEST_OVERWRITE", "a much longer value"))
+        );
+        assert_eq!(
+            shorter,
+            Ok(inherited + pair_len("COMMAND_LIMITS_TEST_OVERWRITE", "v"))
+        );
+    }
+
+    #[test]
+    fn setting_same_value_changes_nothing() {
+        let limits = CommandLimits {
+            env_count: NonZeroUsize::new(1),
+            ..fixed(4096)
+        };
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+        cmd.env("KEY", "value").unwrap();
+        let env_size = cmd.env_size();
+
+        cmd.env("KEY", "value").unwrap();
+        assert_eq!(cmd.env_size(), env_size);
+        assert_eq!(cmd.env_size(), pair_len("KEY", "value"));
+        assert_eq!(cmd.get_envs().count(), 1);
+    }
+
+    #[test]
+    fn coalesce_env_frees_env_count() {
+        let _env = lock_env();
+        env::set_var("COMMAND_LIMITS_TEST_COALESCE", "value");
+        let limits = CommandLimits {
+            env_count: NonZeroUsize::new(1),
+            ..fixed(1 << 20)
+        };
+        let mut cmd = CommandBuilder::with_limits("echo", limits).unwrap();
+        let inherited = cmd.env_size();
+
+        cmd.env("COMMAND_LIMITS_TEST_COALESCE", "value").unwrap();
+        assert!(matches!(
+            cmd.env("KEY", "value"),
+            Err(Error::TooMany {
+                limit: 1,
+                domain: Domain::Env
+            })
+        ));
+
+        cmd.coalesce_env();
+        let res = cmd.env("KEY", "value").map(|_| ());
+        env::remove_var("COMMAND_LIMITS_TEST_COALESCE");
+        assert_eq!(res, Ok(()));
+        assert_eq!(cmd.env_size(), inherited + pair_len("KEY", "value"));
+    }
+
+    #[test]
+    fn replacing_past_individual_env_size_is_too_large() {
+        let limits = CommandLimits {
+            individual_env_size: NonZeroUsize::new(pair_len("KEY", "value")),
+            ..fixed(4096)
+        };
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+        cmd.env("KEY", "v").unwrap();
+
+        assert_eq!(cmd.env("KEY", "value!").unwrap_err(), Error::TooLarge);
+        assert_eq!(cmd.get_env("KEY").as_deref(), Some(OsStr::new("v")));
+        assert_eq!(cmd.env_size(), pair_len("KEY", "v"));
+
+        cmd.env("KEY", "value").unwrap();
+        assert_eq!(cmd.env_size(), pair_len("KEY", "value"));
+    }
+
+    #[test]
+    fn replacing_value_tracks_env_size() {
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", fixed(4096)).unwrap();
+        cmd.env("OTHER", "x").unwrap();
+        cmd.env("KEY", "value").unwrap();
+
+        cmd.env("KEY", "v").unwrap();
+        assert_eq!(
+            cmd.env_size(),
+            pair_len("OTHER", "x") + pair_len("KEY", "v")
+        );
+
+        cmd.env("KEY", "a much longer value").unwrap();
+        assert_eq!(
+            cmd.env_size(),
+            pair_len("OTHER", "x") + pair_len("KEY", "a much longer value")
+        );
+        assert_eq!(
+            cmd.env_sizes().map(|(_, len)| len).sum::<usize>(),
+            cmd.env_size()
+        );
+    }
+
+    #[test]
+    fn replacing_value_checks_space_for_growth_only() {
+        let limits = fixed(100);
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+        let free = cmd.remaining_env_space();
+        let value = "x".repeat(free - pair_len("KEY", ""));
+        cmd.env("KEY", &value).unwrap();
+        assert_eq!(cmd.remaining_env_space(), 0);
+
+        // Replacing a full value with one of the same size needs no more space
+        cmd.env("KEY", "y".repeat(value.len())).unwrap();
+        assert!(matches!(
+            cmd.env("KEY", format!("{}z", value)),
+            Err(Error::InsufficientSpace { .. })
+        ));
+        cmd.env("KEY", "v").unwrap();
+        assert_eq!(cmd.env_size(), pair_len("KEY", "v"));
+    }
+
+    #[test]
+    fn cleared_env_ignores_inherited_variables() {
+        let _env = lock_env();
+        env::set_var("COMMAND_LIMITS_TEST_INHERITED", "value");
+        let mut cmd = CommandBuilder::new_empty_env("echo").unwrap();
+
+        let freed = cmd.try_env_remove("COMMAND_LIMITS_TEST_INHERITED");
+        let removed = (cmd.env_size(), cmd.get_envs().count());
+        let set = cmd
+            .env("COMMAND_LIMITS_TEST_INHERITED", "v")
+            .map(|cmd| cmd.env_size());
+        env::remove_var("COMMAND_LIMITS_TEST_INHERITED");
+
+        assert_eq!(freed, 0);
+        assert_eq!(removed, (0, 0));
+        assert_eq!(set, Ok(pair_len("COMMAND_LIMITS_TEST_INHERITED", "v")));
+    }
+
+    #[test]
+    fn captured_env_removal() {
+        let mut cmd = CommandBuilder::new_capture_env("echo").unwrap();
+        cmd.env("KEY", "value").unwrap();
+        let env_size = cmd.env_size();
+        let count = cmd.get_envs().count();
+
+        assert_eq!(cmd.try_env_remove("KEY"), pair_len("KEY", "value"));
+        assert_eq!(cmd.env_size(), env_size - pair_len("KEY", "value"));
+        assert_eq!(cmd.get_envs().count(), count - 1);
+
+        // Removing it again, or a variable never captured, changes nothing
+        assert_eq!(cmd.try_env_remove("KEY"), 0);
+        assert_eq!(cmd.try_env_remove("COMMAND_LIMITS_TEST_NEVER_SET"), 0);
+        assert_eq!(cmd.env_size(), env_size - pair_len("KEY", "value"));
+        assert_eq!(cmd.get_envs().count(), count - 1);
+    }
+
+    #[test]
+    fn removal_frees_env_count() {
+        let limits = CommandLimits {
+            env_count: NonZeroUsize::new(2),
+            ..fixed(4096)
+        };
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+        cmd.env("A", "1").unwrap().env("B", "2").unwrap();
+        assert!(matches!(
+            cmd.env("C", "3"),
+            Err(Error::TooMany {
+                limit: 2,
+                domain: Domain::Env
+            })
+        ));
+
+        cmd.env_remove("A");
+        cmd.env("C", "3").unwrap();
+
+        // Re-adding the removed variable is checked like any other
+        assert!(matches!(
+            cmd.env("A", "1"),
+            Err(Error::TooMany {
+                limit: 2,
+                domain: Domain::Env
+            })
+        ));
+    }
+
+    #[test]
+    fn removal_markers_do_not_count() {
+        let limits = CommandLimits {
+            env_count: NonZeroUsize::new(1),
+            ..fixed(1 << 20)
+        };
+        let mut cmd = CommandBuilder::with_limits("echo", limits).unwrap();
+        cmd.env_remove("COMMAND_LIMITS_TEST_REMOVED_A")
+            .env_remove("COMMAND_LIMITS_TEST_REMOVED_B");
+        cmd.env("KEY", "value").unwrap();
+        assert_eq!(cmd.get_envs().count(), 3);
+    }
+
+    #[test]
+    fn re_adding_removed_variable_checks_size() {
+        let mut cmd = separate_pools();
+        let value = "x".repeat(cmd.remaining_env_space() - pair_len("A", ""));
+        cmd.env("A", &value).unwrap();
+        cmd.env_remove("A");
+        assert_eq!(cmd.env_size(), 0);
+
+        cmd.env("B", "1").unwrap();
+        assert!(matches!(
+            cmd.env("A", &value),
+            Err(Error::InsufficientSpace {
+                domain: Domain::Env,
+                ..
+            })
+        ));
+        assert_eq!(cmd.env_size(), pair_len("B", "1"));
+        assert_eq!(cmd.get_env("A"), None);
+    }
+
+    #[test]
+    fn argv_matches_command() {
+        let mut cmd = CommandBuilder::new_empty_env("find").unwrap();
+        cmd.args(&[".", "-exec", "ls", "{}", "+"])
+            .unwrap()
+            .placeholder("{}");
+        cmd.arg("a").unwrap().arg("b").unwrap();
+
+        let command = cmd.into_command();
+        let mut argv = cmd.argv();
+        assert_eq!(argv.next(), Some(command.get_program()));
+        assert_eq!(cmd.argv().next(), Some(cmd.get_program()));
+        assert!(argv.eq(command.get_args()));
+        assert!(cmd.get_args().eq(command.get_args()));
+        assert!(cmd.get_args().eq([".", "-exec", "ls", "a", "b", "+"]));
+    }
+
+    #[test]
+    fn clones_share_environment_until_changed() {
+        let mut base = CommandBuilder::new_empty_env("echo").unwrap();
+        base.env("KEY", "value").unwrap();
+
+        let mut cmd = base.clone();
+        cmd.env_remove("OTHER");
+        assert!(Arc::ptr_eq(&base.env, &cmd.env));
+
+        cmd.env_remove("KEY");
+        assert!(!Arc::ptr_eq(&base.env, &cmd.env));
+        assert_eq!(base.get_env("KEY").as_deref(), Some(OsStr::new("value")));
+        assert_eq!(cmd.get_env("KEY"), None);
+    }
+
+    fn os_strings(items: &[&str]) -> Vec<OsString> {
+        items.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn check_plan_agrees_with_builder() {
+        let env = [(OsString::from("K"), OsString::from("v"))];
+        for len in 0..40 {
+            let long = "x".repeat(len);
+            let argv = os_strings(&["echo", "a", &long, &long]);
+            let separate = CommandLimits {
+                env_size: NonZeroUsize::new(30),
+                ..fixed(60)
+            };
+            for limits in [fixed(30), fixed(60), separate] {
+                let built =
+                    CommandBuilder::empty_env_with_limits("echo", limits).and_then(|mut cmd| {
+                        cmd.args(&argv[1..])?.env("K", "v")?;
+                        Ok(())
+                    });
+                assert_eq!(
+                    check_plan(&limits, &argv, &env),
+                    built,
+                    "{} {:?}",
+                    len,
+                    limits
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn check_plan_replaces_duplicate_env_keys() {
+        let limits = CommandLimits {
+            env_count: NonZeroUsize::new(1),
+            ..fixed(100)
+        };
+        let argv = os_strings(&["echo"]);
+        let pair = |key: &str, val: &str| (OsString::from(key), OsString::from(val));
+
+        assert_eq!(
+            check_plan(&limits, &argv, &[pair("A", "1"), pair("A", "2")]),
+            Ok(())
+        );
+        assert_eq!(
+            check_plan(&limits, &argv, &[pair("A", "1"), pair("B", "2")]),
+            Err(Error::TooMany {
+                limit: 1,
+                domain: Domain::Env
+            })
+        );
+    }
+
+    #[test]
+    fn check_plan_needs_a_program_that_fits() {
+        let limits = fixed(100);
+        assert_eq!(check_plan(&limits, &[], &[]), Err(Error::EmptyCommand));
+        assert_eq!(
+            check_plan(&limits, &os_strings(&[""]), &[]),
+            Err(Error::EmptyCommand)
+        );
+        assert_eq!(
+            check_plan(&fixed(1), &os_strings(&["echo"]), &[]),
+            Err(Error::TooLarge)
+        );
+        assert_eq!(
+            check_plan(&limits, &os_strings(&["echo", "a\0b"]), &[]),
+            Err(Error::ContainsNul {
+                domain: Domain::Arg
+            })
+        );
+    }
+
+    #[test]
+    fn response_file_only_when_over_system_limits() {
+        let dir = env::temp_dir();
+        let mut cmd = CommandBuilder::empty_env_with_limits("cc", fixed(usize::MAX / 4)).unwrap();
+        cmd.args(&["-o", "a b"]).unwrap();
+
+        let (small, response) = cmd.into_command_with_response_file(&dir).unwrap();
+        assert!(response.is_none());
+        assert!(small.get_args().eq(["-o", "a b"]));
+
+        let long = "x".repeat(CommandLimits::detect().arg_size.get());
+        cmd.arg(&long).unwrap();
+        let (large, response) = cmd.into_command_with_response_file(&dir).unwrap();
+        let response = response.unwrap();
+        let mut at_file = OsString::from("@");
+        at_file.push(response.path());
+        assert!(large.get_args().eq([&at_file]));
+
+        let mut expected = OsString::new();
+        for arg in ["-o", "a b", &long] {
+            push_quoted_arg(&mut expected, arg.as_ref());
+            expected.push("\n");
+        }
+        let contents = std::fs::read(response.path()).unwrap();
+        assert_eq!(contents, expected.as_encoded_bytes());
+        #[cfg(unix)]
+        assert!(contents.starts_with(b"-o\n'a b'\nxxx"));
+
+        let path = response.path().to_owned();
+        drop(response);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn arg_unique_skips_duplicates() {
+        let mut cmd = CommandBuilder::new_empty_env("echo").unwrap();
+        cmd.arg("a").unwrap();
+
+        assert!(!cmd.arg_unique("a").unwrap());
+        assert!(cmd.arg_unique("b").unwrap());
+        assert!(!cmd.arg_unique("b").unwrap());
+        assert!(cmd.arg_unique("echo").unwrap());
+
+        cmd.arg("c").unwrap();
+        assert!(!cmd.arg_unique("c").unwrap());
+        assert!(cmd.argv().eq(["echo", "a", "b", "echo", "c"]));
+    }
+
+    #[test]
+    fn arg_unique_ignores_placeholder() {
+        let mut cmd = CommandBuilder::new_empty_env("find").unwrap();
+        cmd.args(&[".", "-exec", "ls", "{}", "+"]).unwrap();
+        assert!(!cmd.arg_unique("{}").unwrap());
+
+        cmd.placeholder("{}");
+        assert!(cmd.arg_unique("{}").unwrap());
+        assert!(!cmd.arg_unique("{}").unwrap());
+        assert!(cmd.arg_unique("a").unwrap());
+        assert!(cmd.argv().eq(["find", ".", "-exec", "ls", "{}", "a", "+"]));
+    }
+
+    #[test]
+    fn impossible_items_are_measured_alone() {
+        let cmd = CommandBuilder::empty_env_with_limits("echo", fixed(100)).unwrap();
+        let fits = "x".repeat(100 - sizing::arg_len("echo") - sizing::arg_len(""));
+        let items = [fits.as_str(), &fits, "a", &"y".repeat(100)];
+
+        assert_eq!(cmd.find_impossible_items(&items), [3]);
+        assert_eq!(cmd.batch(items).take(3).filter(Result::is_ok).count(), 3);
+    }
+
+    #[test]
+    fn impossible_items_count_arguments_already_added() {
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", fixed(100)).unwrap();
+        let fits = "x".repeat(100 - sizing::arg_len("echo") - sizing::arg_len(""));
+        assert!(cmd.find_impossible_items(&[&fits]).is_empty());
+
+        cmd.arg("-n").unwrap();
+        assert_eq!(cmd.find_impossible_items(&[fits.as_str(), "a"]), [0]);
+        assert!(cmd.batch([&fits]).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn resolve_program_relative_to_current_dir() {
+        let cwd = env::current_dir().unwrap();
+        let program = Path::new("bin").join("tool");
+        let mut cmd = CommandBuilder::new_empty_env(&program).unwrap();
+        assert_eq!(cmd.resolve_program().unwrap(), cwd.join(&program));
+
+        cmd.current_dir("sub");
+        assert_eq!(
+            cmd.resolve_program().unwrap(),
+            cwd.join("sub").join(&program)
+        );
+
+        let dir = env::temp_dir();
+        cmd.current_dir(&dir);
+        assert_eq!(cmd.resolve_program().unwrap(), dir.join(&program));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_program_searches_relative_path_from_current_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("command-limits-resolve-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("bin")).unwrap();
+        let tool = dir.join("bin").join("tool");
+        std::fs::write(&tool, "").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut cmd = CommandBuilder::new_empty_env("tool").unwrap();
+        cmd.env("PATH", "bin").unwrap().current_dir(&dir);
+        let resolved = cmd.resolve_program();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(resolved.unwrap(), tool);
+    }
+
+    #[test]
+    fn freeze_env_checks_limits() {
+        let _env = lock_env();
+        env::set_var("COMMAND_LIMITS_TEST_FREEZE_A", "a");
+        env::set_var("COMMAND_LIMITS_TEST_FREEZE_B", "b".repeat(50));
+
+        let limits = CommandLimits {
+            env_count: NonZeroUsize::new(1),
+            ..CommandLimits::default()
+        };
+        let mut cmd = CommandBuilder::with_limits("echo", limits).unwrap();
+        let env_size = cmd.env_size();
+        assert!(matches!(
+            cmd.freeze_env(),
+            Err(Error::TooMany {
+                domain: Domain::Env,
+                ..
+            })
+        ));
+        assert_eq!(cmd.env_size(), env_size);
+        assert!(cmd.env_clear().is_ok());
+
+        let limits = CommandLimits {
+            individual_env_size: NonZeroUsize::new(40),
+            ..CommandLimits::default()
+        };
+        let mut cmd = CommandBuilder::with_limits("echo", limits).unwrap();
+        assert_eq!(cmd.freeze_env().unwrap_err(), Error::TooLarge);
+        assert!(cmd.env_clear().is_ok());
+
+        env::remove_var("COMMAND_LIMITS_TEST_FREEZE_A");
+        env::remove_var("COMMAND_LIMITS_TEST_FREEZE_B");
+    }
+
+    #[test]
+    fn inherit_env_limited_checks_env_count() {
+        let _env = lock_env();
+        env::set_var("COMMAND_LIMITS_TEST_LIMITED_A", "a");
+        env::set_var("COMMAND_LIMITS_TEST_LIMITED_B", "b");
+
+        let limits = CommandLimits {
+            env_count: NonZeroUsize::new(1),
+            ..CommandLimits::default()
+        };
+        let mut cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+        assert!(matches!(
+            cmd.inherit_env_limited(2),
+            Err(Error::TooMany {
+                domain: Domain::Env,
+                ..
+            })
+        ));
+        assert_eq!(cmd.env_size(), 0);
+        cmd.inherit_env_limited(1).unwrap();
+        assert_eq!(cmd.effective_env_count(), 1);
+
+        env::remove_var("COMMAND_LIMITS_TEST_LIMITED_A");
+        env::remove_var("COMMAND_LIMITS_TEST_LIMITED_B");
+    }
+
+    #[test]
+    fn windows_arg_units_escapes() {
+        let cases = [
+            ("", (0, 0)),
+            ("abc", (3, 0)),
+            ("a b", (3, 0)),
+            (" ", (1, 0)),
+            ("\"", (1, 1)),
+            ("a\"b", (3, 1)),
+            ("\" \"", (3, 2)),
+            ("a\\b", (3, 0)),
+            ("a\\\"b", (4, 2)),
+            ("a\\\\\"b", (5, 3)),
+            ("a\\", (2, 1)),
+            ("a b\\\\", (5, 2)),
+            ("\\\\a b\\\\", (7, 2)),
+            ("\u{1F600}", (2, 0)),
+        ];
+
+        for (arg, expected) in cases {
+            assert_eq!(windows_arg_units(arg.encode_utf16()), expected, "{:?}", arg);
+        }
+    }
+
+    #[test]
+    fn plan_fits_exactly() {
+        let cmd = CommandBuilder::empty_env_with_limits("echo", fixed(100)).unwrap();
+        let env = [(OsString::from("KEY"), OsString::from("value"))];
+        let space = 100 - sizing::arg_len("echo") - pair_len("KEY", "value");
+        let last = "x".repeat(space - sizing::arg_len("a") - sizing::arg_len(""));
+
+        assert_eq!(cmd.plan_fits(&["a", &last], &env), Ok(()));
+        assert!(matches!(
+            cmd.plan_fits(&["a", &format!("{}x", last)], &env),
+            Err(Error::InsufficientSpace {
+                domain: Domain::Arg,
+                ..
+            })
+        ));
+        assert!(cmd.plan_fits(&["a", &last, ""], &env).is_err());
+
+        assert_eq!(cmd.arg_size(), sizing::arg_len("echo"));
+        assert_eq!(cmd.env_size(), 0);
+        assert!(cmd.argv().eq(["echo"]));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn duplicate_inherited_keys_counted_once() {
+        use std::ffi::CString;
+        use std::os::raw::c_char;
+
+        extern "C" {
+            static mut environ: *const *const c_char;
+        }
+
+        let _env = lock_env();
+        env::set_var("COMMAND_LIMITS_TEST_DUPLICATE", "first");
+
+        // Add a second definition of the variable, which set_var would replace
+        let (inherited, captured) = unsafe {
+            let original = environ;
+            let mut vars = vec![];
+            let mut var = original;
+            while !(*var).is_null() {
+                vars.push(*var);
+                var = var.add(1);
+            }
+            let duplicate = CString::new("COMMAND_LIMITS_TEST_DUPLICATE=second").unwrap();
+            vars.push(duplicate.into_raw().cast_const());
+            vars.push(std::ptr::null());
+            // Leaked, as another thread may still be reading it once it's replaced
+            environ = vars.leak().as_ptr();
+
+            let inherited = CommandBuilder::new("echo").unwrap();
+            let captured = CommandBuilder::new_capture_env("echo").unwrap();
+            let raw: usize = env::vars_os()
+                .map(|(k, v)| sizing::env_pair_len(&k, &v))
+                .sum();
+            environ = original;
+            assert!(captured.env_size() < raw);
+            (inherited, captured)
+        };
+        env::remove_var("COMMAND_LIMITS_TEST_DUPLICATE");
+
+        let deduped: usize = captured
+            .get_envs()
+            .map(|(k, v)| sizing::env_pair_len(k, v.unwrap()))
+            .sum();
+        assert_eq!(captured.env_size(), deduped);
+        assert_eq!(inherited.env_size(), deduped);
+        let duplicates = captured
+            .get_envs()
+            .filter(|(k, _)| *k == "COMMAND_LIMITS_TEST_DUPLICATE")
+            .count();
+        assert_eq!(duplicates, 1);
+    }
+
+    #[test]
+    fn count_batches_matches_batch() {
+        let items: Vec<String> = (0..200).map(|i| "x".repeat(i % 37)).collect();
+
+        for arg_size in [64, 100, 257, 1000, 100_000] {
+            for max_data_args in [None, NonZeroUsize::new(3)] {
+                let mut cmd =
+                    CommandBuilder::empty_env_with_limits("echo", fixed(arg_size)).unwrap();
+                cmd.set_max_data_args(max_data_args).unwrap();
+                let items = &items[..items.len().min(arg_size / 2)];
+
+                let batched = cmd.batch(items).map(Result::unwrap).count();
+                assert_eq!(cmd.count_batches(items), Ok(batched), "{}", arg_size);
+                assert_eq!(cmd.arg_size(), sizing::arg_len("echo"));
+            }
+        }
+    }
+
+    #[test]
+    fn count_batches_too_large() {
+        let limits = CommandLimits {
+            individual_arg_size: NonZeroUsize::new(20),
+            ..fixed(100)
+        };
+        let cmd = CommandBuilder::empty_env_with_limits("echo", limits).unwrap();
+
+        assert_eq!(cmd.count_batches::<&str>(&[]), Ok(0));
+        assert_eq!(cmd.count_batches(&["a", "b"]), Ok(1));
+        assert_eq!(
+            cmd.count_batches(&["a", "b", &"x".repeat(20)]),
+            Err(Error::TooLarge)
+        );
+    }
+
+    #[test]
+    fn new_empty_env_inherits_nothing() {
+        let _env = lock_env();
+        env::set_var("COMMAND_LIMITS_TEST_EMPTY", "value");
+        let cmd = CommandBuilder::new_empty_env("env").unwrap();
+        env::remove_var("COMMAND_LIMITS_TEST_EMPTY");
+
+        assert_eq!(cmd.env_size(), 0);
+        assert_eq!(cmd.effective_env_count(), 0);
+        assert_eq!(cmd.get_envs().count(), 0);
+        assert_eq!(cmd.get_env("PATH"), None);
+        assert_eq!(cmd.estimated_total_bytes(), cmd.arg_size());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn new_empty_env_clears_command_env() {
+        let _env = lock_env();
+        env::set_var("COMMAND_LIMITS_TEST_CLEARED", "value");
+        let output = CommandBuilder::new_empty_env("/usr/bin/env")
+            .unwrap()
+            .into_command()
+            .output();
+        env::remove_var("COMMAND_LIMITS_TEST_CLEARED");
+
+        let output = output.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"");
+    }
+
+    #[test]
+    fn limits_too_small_for_program() {
+        let program = format!("/{}/program", "long-directory-name/".repeat(10));
+        let needed = sizing::arg_len(&program);
+        let expected = Error::LimitTooSmall { needed, limit: 64 };
+
+        let _env = lock_env();
+        assert_eq!(
+            CommandBuilder::with_limits(&program, fixed(64)).unwrap_err(),
+            expected
+        );
+        assert_eq!(
+            CommandBuilder::capture_with_limits(&program, fixed(64)).unwrap_err(),
+            expected
+        );
+        assert_eq!(
+            CommandBuilder::empty_env_with_limits(&program, fixed(64)).unwrap_err(),
+            expected
+        );
+        assert_eq!(
+            CommandBuilder::empty_env_with_limits(&program, fixed(needed).with_reserve(1))
+                .unwrap_err(),
+            Error::LimitTooSmall {
+                needed,
+                limit: needed - 1
+            }
+        );
+    }
+
+    // Run `line` with a POSIX shell, returning the null-terminated strings it prints.
+    #[cfg(unix)]
+    fn run_shell_line(line: &OsStr) -> Vec<OsString> {
+        use std::os::unix::ffi::OsStringExt;
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(line)
+            .env("COMMAND_LIMITS_TEST_SHELL_REMOVED", "inherited")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", line);
+        let mut printed: Vec<_> = output
+            .stdout
+            .split(|&b| b == 0)
+            .map(|s| OsString::from_vec(s.to_vec()))
+            .collect();
+        assert_eq!(printed.pop(), Some(OsString::new()));
+        printed
+    }
+
+    #[cfg(unix)]
+    fn awkward_strings() -> Vec<OsString> {
+        use std::os::unix::ffi::OsStringExt;
+
+        let mut strings = os_strings(&[
+            "it's \"quoted\"",
+            "new\nline",
+            "$HOME `pwd` \\ *",
+            "",
+            "-u",
+            "KEY=value",
+        ]);
+        strings.push(OsString::from_vec(b"non-UTF-8 \xff\xfe".to_vec()));
+        strings
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_command_round_trips_env() {
+        let mut base = CommandBuilder::new("/bin/sh").unwrap();
+        base.args(&[
+            "-c",
+            r#"printf '%s\0' "${COMMAND_LIMITS_TEST_SHELL_SET-unset}" "${COMMAND_LIMITS_TEST_SHELL_REMOVED-unset}""#,
+        ])
+        .unwrap();
+
+        for value in awkward_strings() {
+            let mut cmd = base.clone();
+            cmd.env("COMMAND_LIMITS_TEST_SHELL_SET", &value).unwrap();
+            assert_eq!(
+                run_shell_line(&cmd.to_shell_command()),
+                [value.clone(), "inherited".into()]
+            );
+
+            cmd.env_remove("COMMAND_LIMITS_TEST_SHELL_REMOVED");
+            assert!(cmd.get_envs().eq([
+                (OsStr::new("COMMAND_LIMITS_TEST_SHELL_REMOVED"), None),
+                (OsStr::new("COMMAND_LIMITS_TEST_SHELL_SET"), Some(&*value)),
+            ]));
+            assert_eq!(
+                run_shell_line(&cmd.to_shell_command()),
+                [value.clone(), "unset".into()]
+            );
+
+            cmd.env_clear()
+                .unwrap()
+                .env("COMMAND_LIMITS_TEST_SHELL_SET", &value)
+                .unwrap();
+            assert_eq!(
+                run_shell_line(&cmd.to_shell_command()),
+                [value.clone(), "unset".into()]
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_command_round_trips_keys_needing_env() {
+        let mut cmd = CommandBuilder::new("/usr/bin/env").unwrap();
+        cmd.env_clear()
+            .unwrap()
+            .env("COMMAND-LIMITS TEST", "it's\nhere")
+            .unwrap();
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(cmd.to_shell_command())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"COMMAND-LIMITS TEST=it's\nhere\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_line_round_trips_through_shell() {
+        let mut cmd = CommandBuilder::new_empty_env("printf").unwrap();
+        cmd.arg(r"%s\0").unwrap();
+        cmd.args(&awkward_strings()).unwrap();
+        assert_eq!(run_shell_line(&cmd.to_command_line()), awkward_strings());
     }
 }
@@ -0,0 +1,184 @@
+use std::process::ExitStatus;
+
+/// How `BatchOutcome` combines the exit statuses of several commands into one.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum ExitPolicy {
+    /// The code of the first command to fail, or 0.
+    FirstNonZero,
+    /// The code of the last command run, or 0 if none were.
+    Last,
+    /// The codes used by GNU `xargs`: 124 if a command exited with status 255,
+    /// 125 if one was killed by a signal, 123 if any other failed, or 0.
+    #[default]
+    Xargs,
+}
+
+/// The exit statuses of a series of batched commands, and the policy for turning
+/// them into a single exit code.
+///
+/// Commands killed by a signal have no exit code of their own, and count as 1
+/// under `FirstNonZero` and `Last`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BatchOutcome {
+    policy: ExitPolicy,
+    statuses: Vec<ExitStatus>,
+}
+
+impl BatchOutcome {
+    /// Create an empty outcome using `policy`.
+    pub fn new(policy: ExitPolicy) -> Self {
+        Self {
+            policy,
+            statuses: vec![],
+        }
+    }
+
+    /// Record the exit status of another command.
+    pub fn push(&mut self, status: ExitStatus) {
+        self.statuses.push(status);
+    }
+
+    /// Return the statuses recorded, in order.
+    pub fn statuses(&self) -> &[ExitStatus] {
+        &self.statuses
+    }
+
+    /// Return whether no more commands should be run, as `xargs` stops after one
+    /// exits with status 255 or is killed by a signal.
+    pub fn should_stop(&self) -> bool {
+        self.statuses
+            .last()
+            .is_some_and(|status| matches!(status.code(), Some(255) | None))
+    }
+
+    /// Return the combined exit code, according to the policy.
+    pub fn exit_code(&self) -> i32 {
+        let code = |status: &ExitStatus| status.code().unwrap_or(1);
+
+        match self.policy {
+            ExitPolicy::FirstNonZero => self
+                .statuses
+                .iter()
+                .find(|status| !status.success())
+                .map_or(0, code),
+            ExitPolicy::Last => self.statuses.last().map_or(0, code),
+            ExitPolicy::Xargs => {
+                let mut rc = 0;
+                for status in &self.statuses {
+                    match status.code() {
+                        Some(0) => (),
+                        Some(255) => return 124,
+                        Some(_) => rc = 123,
+                        None => return 125,
+                    }
+                }
+                rc
+            }
+        }
+    }
+}
+
+impl Extend<ExitStatus> for BatchOutcome {
+    fn extend<I: IntoIterator<Item = ExitStatus>>(&mut self, iter: I) {
+        self.statuses.extend(iter);
+    }
+}
+
+impl FromIterator<ExitStatus> for BatchOutcome {
+    /// Collect statuses using the default policy, `ExitPolicy::Xargs`.
+    fn from_iter<I: IntoIterator<Item = ExitStatus>>(iter: I) -> Self {
+        Self {
+            policy: Default::default(),
+            statuses: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    fn killed(signal: i32) -> ExitStatus {
+        ExitStatus::from_raw(signal)
+    }
+
+    fn exit_code(policy: ExitPolicy, statuses: &[ExitStatus]) -> i32 {
+        let mut outcome = BatchOutcome::new(policy);
+        outcome.extend(statuses.iter().copied());
+        outcome.exit_code()
+    }
+
+    #[test]
+    fn xargs_policy() {
+        let cases = [
+            (vec![], 0),
+            (vec![exited(0), exited(0)], 0),
+            (vec![exited(0), exited(1), exited(0)], 123),
+            (vec![exited(2), exited(125)], 123),
+            (vec![exited(1), exited(255)], 124),
+            (vec![exited(0), killed(9)], 125),
+            (vec![killed(15), exited(255)], 125),
+        ];
+
+        for (statuses, expected) in cases {
+            assert_eq!(
+                exit_code(ExitPolicy::Xargs, &statuses),
+                expected,
+                "{:?}",
+                statuses
+            );
+        }
+        assert_eq!(
+            [exited(0), exited(3)]
+                .into_iter()
+                .collect::<BatchOutcome>()
+                .exit_code(),
+            123
+        );
+    }
+
+    #[test]
+    fn first_non_zero_policy() {
+        assert_eq!(exit_code(ExitPolicy::FirstNonZero, &[]), 0);
+        assert_eq!(
+            exit_code(ExitPolicy::FirstNonZero, &[exited(0), exited(0)]),
+            0
+        );
+        assert_eq!(
+            exit_code(ExitPolicy::FirstNonZero, &[exited(0), exited(3), exited(4)]),
+            3
+        );
+        assert_eq!(
+            exit_code(ExitPolicy::FirstNonZero, &[killed(9), exited(4)]),
+            1
+        );
+    }
+
+    #[test]
+    fn last_policy() {
+        assert_eq!(exit_code(ExitPolicy::Last, &[]), 0);
+        assert_eq!(exit_code(ExitPolicy::Last, &[exited(3), exited(0)]), 0);
+        assert_eq!(exit_code(ExitPolicy::Last, &[exited(0), exited(4)]), 4);
+        assert_eq!(exit_code(ExitPolicy::Last, &[exited(0), killed(9)]), 1);
+    }
+
+    #[test]
+    fn stops_after_255_or_signal() {
+        let mut outcome = BatchOutcome::default();
+        assert!(!outcome.should_stop());
+        outcome.push(exited(1));
+        assert!(!outcome.should_stop());
+        outcome.push(exited(255));
+        assert!(outcome.should_stop());
+
+        let mut outcome = BatchOutcome::default();
+        outcome.push(killed(9));
+        assert!(outcome.should_stop());
+        assert_eq!(outcome.statuses(), [killed(9)]);
+    }
+}
@@ -0,0 +1,230 @@
+use std::env;
+use std::error;
+use std::ffi::OsString;
+use std::fmt;
+use std::num::NonZeroUsize;
+
+use crate::CommandLimits;
+
+/// An environment variable read by `CommandLimits::from_env` which does not hold
+/// a valid value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvalidOverride {
+    /// The name of the variable.
+    pub var: &'static str,
+    /// The value it holds.
+    pub value: OsString,
+}
+
+impl fmt::Display for InvalidOverride {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid value for {}: {:?}",
+            self.var,
+            self.value.to_string_lossy()
+        )
+    }
+}
+
+impl error::Error for InvalidOverride {}
+
+// Read a number from `var`, if it's set.
+fn read(var: &'static str) -> Result<Option<usize>, InvalidOverride> {
+    env::var_os(var)
+        .map(|value| {
+            value
+                .to_str()
+                .and_then(|s| s.parse().ok())
+                .ok_or(InvalidOverride { var, value })
+        })
+        .transpose()
+}
+
+// Read an optional limit from `var`, where 0 means no limit.
+fn read_limit(
+    var: &'static str,
+    default: Option<NonZeroUsize>,
+) -> Result<Option<NonZeroUsize>, InvalidOverride> {
+    Ok(read(var)?.map_or(default, NonZeroUsize::new))
+}
+
+impl CommandLimits {
+    /// Return the default limits, with any overridden by environment variables.
+    ///
+    /// Each variable holds a number, and any left unset keeps its default:
+    ///
+    /// * `COMMAND_LIMITS_ARG_SIZE`, which must be non-zero
+    /// * `COMMAND_LIMITS_INDIVIDUAL_ARG_SIZE`
+    /// * `COMMAND_LIMITS_ARG_COUNT`
+    /// * `COMMAND_LIMITS_ENV_SIZE`
+    /// * `COMMAND_LIMITS_INDIVIDUAL_ENV_SIZE`
+    /// * `COMMAND_LIMITS_ENV_COUNT`
+    /// * `COMMAND_LIMITS_TOTAL_SIZE`
+    /// * `COMMAND_LIMITS_RESERVE`
+    ///
+    /// The optional limits may be given as 0 to leave them unset.
+    pub fn from_env() -> Result<Self, InvalidOverride> {
+        let default = Self::default();
+
+        let arg_size = match read("COMMAND_LIMITS_ARG_SIZE")? {
+            Some(size) => NonZeroUsize::new(size).ok_or(InvalidOverride {
+                var: "COMMAND_LIMITS_ARG_SIZE",
+                value: "0".into(),
+            })?,
+            None => default.arg_size,
+        };
+
+        Ok(Self {
+            arg_size,
+            individual_arg_size: read_limit(
+                "COMMAND_LIMITS_INDIVIDUAL_ARG_SIZE",
+                default.individual_arg_size,
+            )?,
+            arg_count: read_limit("COMMAND_LIMITS_ARG_COUNT", default.arg_count)?,
+            env_size: read_limit("COMMAND_LIMITS_ENV_SIZE", default.env_size)?,
+            individual_env_size: read_limit(
+                "COMMAND_LIMITS_INDIVIDUAL_ENV_SIZE",
+                default.individual_env_size,
+            )?,
+            env_count: read_limit("COMMAND_LIMITS_ENV_COUNT", default.env_count)?,
+            total_size: read_limit("COMMAND_LIMITS_TOTAL_SIZE", default.total_size)?,
+            reserve: read("COMMAND_LIMITS_RESERVE")?.unwrap_or(default.reserve),
+            ..default
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_env;
+
+    const VARS: [&str; 8] = [
+        "COMMAND_LIMITS_ARG_SIZE",
+        "COMMAND_LIMITS_INDIVIDUAL_ARG_SIZE",
+        "COMMAND_LIMITS_ARG_COUNT",
+        "COMMAND_LIMITS_ENV_SIZE",
+        "COMMAND_LIMITS_INDIVIDUAL_ENV_SIZE",
+        "COMMAND_LIMITS_ENV_COUNT",
+        "COMMAND_LIMITS_TOTAL_SIZE",
+        "COMMAND_LIMITS_RESERVE",
+    ];
+
+    // Run `f` with only `vars` of the overrides set, and clear them again after.
+    fn with_vars<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _env = lock_env();
+        let clear = || {
+            for var in VARS {
+                env::remove_var(var);
+            }
+        };
+
+        clear();
+        for (var, value) in vars {
+            env::set_var(var, value);
+        }
+        let res = f();
+        clear();
+        res
+    }
+
+    #[test]
+    fn from_env_missing_keeps_defaults() {
+        let limits = with_vars(&[], CommandLimits::from_env).unwrap();
+        let default = CommandLimits::default();
+
+        assert_eq!(limits.arg_size, default.arg_size);
+        assert_eq!(limits.individual_arg_size, default.individual_arg_size);
+        assert_eq!(limits.arg_count, default.arg_count);
+        assert_eq!(limits.env_size, default.env_size);
+        assert_eq!(limits.env_count, default.env_count);
+        assert_eq!(limits.total_size, default.total_size);
+        assert_eq!(limits.reserve, default.reserve);
+    }
+
+    #[test]
+    fn from_env_valid() {
+        let limits = with_vars(
+            &[
+                ("COMMAND_LIMITS_ARG_SIZE", "4096"),
+                ("COMMAND_LIMITS_ARG_COUNT", "10"),
+                ("COMMAND_LIMITS_ENV_SIZE", "2048"),
+                ("COMMAND_LIMITS_TOTAL_SIZE", "8192"),
+                ("COMMAND_LIMITS_RESERVE", "16"),
+            ],
+            CommandLimits::from_env,
+        )
+        .unwrap();
+
+        assert_eq!(limits.arg_size.get(), 4096);
+        assert_eq!(limits.arg_count, NonZeroUsize::new(10));
+        assert_eq!(limits.env_size, NonZeroUsize::new(2048));
+        assert_eq!(limits.total_size, NonZeroUsize::new(8192));
+        assert_eq!(limits.reserve, 16);
+        assert_eq!(limits.env_count, CommandLimits::default().env_count);
+    }
+
+    #[test]
+    fn from_env_invalid() {
+        for (var, value) in [
+            ("COMMAND_LIMITS_ARG_SIZE", "lots"),
+            ("COMMAND_LIMITS_ARG_COUNT", "-1"),
+            ("COMMAND_LIMITS_ENV_SIZE", "1.5"),
+            ("COMMAND_LIMITS_RESERVE", ""),
+        ] {
+            assert_eq!(
+                with_vars(&[(var, value)], CommandLimits::from_env).unwrap_err(),
+                InvalidOverride {
+                    var,
+                    value: value.into()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn from_env_zero() {
+        assert_eq!(
+            with_vars(&[("COMMAND_LIMITS_ARG_SIZE", "0")], CommandLimits::from_env).unwrap_err(),
+            InvalidOverride {
+                var: "COMMAND_LIMITS_ARG_SIZE",
+                value: "0".into()
+            }
+        );
+
+        let limits = with_vars(
+            &[
+                ("COMMAND_LIMITS_INDIVIDUAL_ARG_SIZE", "0"),
+                ("COMMAND_LIMITS_ENV_COUNT", "0"),
+                ("COMMAND_LIMITS_RESERVE", "0"),
+            ],
+            CommandLimits::from_env,
+        )
+        .unwrap();
+        assert_eq!(limits.individual_arg_size, None);
+        assert_eq!(limits.env_count, None);
+        assert_eq!(limits.reserve, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn from_env_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let value = std::ffi::OsStr::from_bytes(b"12\xff");
+        let err = {
+            let _env = lock_env();
+            env::set_var("COMMAND_LIMITS_ENV_SIZE", value);
+            let res = CommandLimits::from_env();
+            env::remove_var("COMMAND_LIMITS_ENV_SIZE");
+            res.unwrap_err()
+        };
+        assert_eq!(err.var, "COMMAND_LIMITS_ENV_SIZE");
+        assert_eq!(err.value, value);
+        assert_eq!(
+            err.to_string(),
+            "invalid value for COMMAND_LIMITS_ENV_SIZE: \"12\u{FFFD}\""
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Readers for splitting input into command arguments, as `xargs` does.
+
+use std::io::{self, BufRead};
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read the next whitespace-delimited word from `reader`, in the manner of POSIX
+/// `xargs`.
+///
+/// Backslash escapes the following byte, and single or double quotes may be used to
+/// include whitespace, though quoted strings may not span lines.  Returns `None` at
+/// the end of input, or an `InvalidData` error for an unterminated quote or a
+/// backslash at the end of input.
+pub fn read_shell_word<R: BufRead>(reader: &mut R) -> Option<io::Result<Vec<u8>>> {
+    let mut item = vec![];
+    let mut complete = false;
+    let mut escape = false;
+    let mut single = false;
+    let mut double = false;
+
+    while !complete {
+        let buffer = match reader.fill_buf() {
+            Ok(buffer) => buffer,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if buffer.is_empty() {
+            if single || double {
+                return Some(Err(invalid_data("unterminated quote")));
+            } else if escape {
+                return Some(Err(invalid_data("backslash at EOF")));
+            } else if item.is_empty() {
+                return None;
+            }
+            break;
+        }
+
+        let mut consumed = 0;
+        for &byte in buffer {
+            consumed += 1;
+            if escape {
+                escape = false;
+                item.push(byte);
+            } else if single || double {
+                match byte {
+                    b'\'' if single => single = false,
+                    b'"' if double => double = false,
+                    b'\n' => return Some(Err(invalid_data("unterminated quote"))),
+                    _ => item.push(byte),
+                }
+            } else {
+                match byte {
+                    b'\\' => escape = true,
+                    b'\'' => single = true,
+                    b'"' => double = true,
+                    _ if byte.is_ascii_whitespace() => complete = !item.is_empty(),
+                    _ => item.push(byte),
+                }
+            }
+            if complete {
+                break;
+            }
+        }
+        reader.consume(consumed);
+    }
+
+    Some(Ok(item))
+}
+
+/// Read the next NUL-terminated item from `reader`, as with `xargs -0`.
+///
+/// Trailing data without a final NUL is returned as the last item.  Returns `None`
+/// at the end of input.
+pub fn read_null_delimited<R: BufRead>(reader: &mut R) -> Option<io::Result<Vec<u8>>> {
+    let mut item = vec![];
+
+    match reader.read_until(b'\0', &mut item) {
+        Ok(0) => None,
+        Ok(_) => {
+            if item.last() == Some(&b'\0') {
+                item.pop();
+            }
+            Some(Ok(item))
+        }
+        Err(e) => Some(Err(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    // Read every item up to the first error, with a small buffer so items span
+    // several reads.
+    fn read_all<'a, F>(input: &'a [u8], mut read: F) -> Vec<io::Result<Vec<u8>>>
+    where
+        F: FnMut(&mut BufReader<&'a [u8]>) -> Option<io::Result<Vec<u8>>>,
+    {
+        let mut reader = BufReader::with_capacity(3, input);
+        let mut failed = false;
+        std::iter::from_fn(|| {
+            let item = read(&mut reader).filter(|_| !failed)?;
+            failed = item.is_err();
+            Some(item)
+        })
+        .collect()
+    }
+
+    fn words(input: &[u8]) -> Vec<Vec<u8>> {
+        read_all(input, read_shell_word)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn word_error(input: &[u8]) -> String {
+        let mut items = read_all(input, read_shell_word);
+        let error = items.pop().unwrap().unwrap_err();
+        assert!(items.iter().all(Result::is_ok));
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        error.to_string()
+    }
+
+    #[test]
+    fn shell_words() {
+        assert_eq!(
+            words(b"a bb\tccc\n  dddd  "),
+            ["a", "bb", "ccc", "dddd"].map(str::as_bytes)
+        );
+        assert_eq!(
+            words(br#"'a b' "c 'd'" e\ f g\\h"#),
+            ["a b", "c 'd'", "e f", "g\\h"].map(str::as_bytes)
+        );
+        assert_eq!(words(b"a'b c'd\n"), [b"ab cd"]);
+        assert_eq!(words(b"\\\n x"), [&b"\n"[..], b"x"]);
+        assert_eq!(words(b"\xff\xfe \x01"), [&b"\xff\xfe"[..], b"\x01"]);
+    }
+
+    #[test]
+    fn shell_words_empty_input() {
+        assert!(words(b"").is_empty());
+        assert!(words(b" \n\t \n").is_empty());
+    }
+
+    #[test]
+    fn shell_words_invalid() {
+        assert_eq!(word_error(b"a 'b"), "unterminated quote");
+        assert_eq!(word_error(b"a \"b"), "unterminated quote");
+        assert_eq!(word_error(b"'a\nb'"), "unterminated quote");
+        assert_eq!(word_error(b"a b\\"), "backslash at EOF");
+    }
+
+    #[test]
+    fn shell_words_long_input() {
+        let long = vec![b'x'; 100_000];
+        let mut input = long.clone();
+        input.extend_from_slice(b" '");
+        input.extend_from_slice(&long);
+        input.extend_from_slice(b"'\n");
+
+        assert_eq!(words(&input), [long.clone(), long]);
+    }
+
+    #[test]
+    fn null_delimited() {
+        let items: Vec<_> = read_all(b"a b\0\0c\nd\0e", read_null_delimited)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(items, ["a b", "", "c\nd", "e"].map(str::as_bytes));
+
+        assert!(read_all(b"", read_null_delimited).is_empty());
+        let long = vec![b'x'; 100_000];
+        let items = read_all(&long, read_null_delimited);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].as_ref().unwrap(), &long);
+    }
+}
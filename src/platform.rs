@@ -0,0 +1,120 @@
+use std::ffi::OsStr;
+
+use crate::{imp, PointerSize};
+
+// Assume 8 bytes, as for the native Unix implementation.
+const UNIX_POINTER_SIZE: usize = 8;
+
+/// The platform conventions used to measure arguments and environment variables.
+///
+/// The current platform is measured exactly.  Other platforms are estimated from
+/// a lossy UTF-8 conversion of each value, which is sufficient for planning but may
+/// differ for strings which are not valid Unicode.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Platform {
+    /// Arguments and environment passed as arrays of C strings.
+    Unix,
+    /// Arguments passed as a single quoted UTF-16 command line, and environment as a
+    /// block of UTF-16 strings.
+    Windows,
+}
+
+impl Platform {
+    /// Return the platform this program is running on.
+    pub const fn current() -> Self {
+        if cfg!(windows) {
+            Platform::Windows
+        } else {
+            Platform::Unix
+        }
+    }
+
+    fn is_current(self) -> bool {
+        self == Self::current()
+    }
+
+    /// Return the space saved for each pointer by using `pointer_size` rather than
+    /// assuming the widest.
+    fn pointer_saving(self, pointer_size: PointerSize) -> usize {
+        match self {
+            Platform::Unix => UNIX_POINTER_SIZE.saturating_sub(pointer_size.bytes()),
+            Platform::Windows => 0,
+        }
+    }
+
+    /// Return the space taken by each pointer in the argument and environment
+    /// arrays, if there are any.
+    pub(crate) fn pointer_len(self, pointer_size: PointerSize) -> usize {
+        match self {
+            Platform::Unix => UNIX_POINTER_SIZE - self.pointer_saving(pointer_size),
+            Platform::Windows => 0,
+        }
+    }
+
+    pub(crate) fn arg_len(self, arg: &OsStr, pointer_size: PointerSize) -> usize {
+        self.wide_arg_len(arg) - self.pointer_saving(pointer_size)
+    }
+
+    fn wide_arg_len(self, arg: &OsStr) -> usize {
+        match self {
+            _ if self.is_current() => imp::arg_len(arg),
+            Platform::Unix => self.wide_arg_len_for(arg.to_string_lossy().len()),
+            Platform::Windows => {
+                let (content, escape) =
+                    crate::windows_arg_units(arg.to_string_lossy().encode_utf16());
+                content + escape + 3
+            }
+        }
+    }
+
+    pub(crate) fn arg_len_for(self, len: usize, pointer_size: PointerSize) -> usize {
+        self.wide_arg_len_for(len) - self.pointer_saving(pointer_size)
+    }
+
+    fn wide_arg_len_for(self, len: usize) -> usize {
+        match self {
+            _ if self.is_current() => imp::arg_len_for(len),
+            Platform::Unix => UNIX_POINTER_SIZE + len + 1,
+            Platform::Windows => len + 3,
+        }
+    }
+
+    pub(crate) fn env_pair_len(self, k: &OsStr, v: &OsStr, pointer_size: PointerSize) -> usize {
+        let len = match self {
+            _ if self.is_current() => imp::env_pair_len(k, v),
+            _ => self.wide_env_pair_len_for(self.units(k), self.units(v)),
+        };
+        len - self.pointer_saving(pointer_size)
+    }
+
+    pub(crate) fn env_pair_len_for(
+        self,
+        key_len: usize,
+        val_len: usize,
+        pointer_size: PointerSize,
+    ) -> usize {
+        self.wide_env_pair_len_for(key_len, val_len) - self.pointer_saving(pointer_size)
+    }
+
+    fn wide_env_pair_len_for(self, key_len: usize, val_len: usize) -> usize {
+        match self {
+            _ if self.is_current() => imp::env_pair_len_for(key_len, val_len),
+            Platform::Unix => UNIX_POINTER_SIZE + key_len + 1 + val_len + 1,
+            Platform::Windows => key_len + 1 + val_len + 1,
+        }
+    }
+
+    // Estimate the length of a string in bytes or UTF-16 code units
+    fn units(self, s: &OsStr) -> usize {
+        match self {
+            Platform::Unix => s.to_string_lossy().len(),
+            Platform::Windows => s.to_string_lossy().encode_utf16().count(),
+        }
+    }
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        Self::current()
+    }
+}
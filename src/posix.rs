@@ -0,0 +1,44 @@
+// Conventions shared by platforms which pass arguments as arrays of C strings,
+// and whose commands are usually run from a POSIX shell.
+
+use std::borrow::Cow;
+
+/// How the space taken by an argument is made up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ArgLenBreakdown {
+    /// The entry in the argv pointer array.
+    pub pointer_bytes: usize,
+    /// The argument itself.
+    pub content_bytes: usize,
+    /// The trailing NUL.
+    pub terminator_bytes: usize,
+}
+
+impl ArgLenBreakdown {
+    /// Return the total, as used for accounting.
+    pub fn total(&self) -> usize {
+        self.pointer_bytes + self.content_bytes + self.terminator_bytes
+    }
+}
+
+// Quote `arg` for a POSIX shell, leaving it bare if it contains nothing the shell
+// would interpret.
+pub(crate) fn shell_quote(arg: &[u8]) -> Cow<'_, [u8]> {
+    let safe = |b: &u8| b.is_ascii_alphanumeric() || b"_@%+=:,./-".contains(b);
+
+    if !arg.is_empty() && arg.iter().all(safe) {
+        return Cow::Borrowed(arg);
+    }
+
+    let mut quoted = Vec::with_capacity(arg.len() + 2);
+    quoted.push(b'\'');
+    for &b in arg {
+        if b == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    Cow::Owned(quoted)
+}
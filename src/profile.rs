@@ -0,0 +1,90 @@
+use std::num::NonZeroUsize;
+
+use serde::{Deserialize, Deserializer};
+
+// Optional limits may be given as 0 to mean no limit, matching the NonZeroUsize
+// conversion used elsewhere.
+pub(crate) fn zero_as_none<'de, D>(deserializer: D) -> Result<Option<NonZeroUsize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<usize>::deserialize(deserializer)?.and_then(NonZeroUsize::new))
+}
+
+#[cfg(feature = "toml")]
+impl crate::CommandLimits {
+    /// Parse a limits profile from TOML.
+    ///
+    /// `arg_size` is required and must be non-zero.  The remaining limits are optional,
+    /// and may be omitted or given as 0 to leave them unset:
+    ///
+    /// ```toml
+    /// arg_size = 262144
+    /// individual_arg_size = 131072
+    /// arg_count = 0
+    /// env_size = 65536
+    /// individual_env_size = 131072
+    /// env_count = 100
+    /// ```
+    pub fn from_toml_str(s: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(s)
+    }
+}
+
+#[cfg(all(test, feature = "toml"))]
+mod tests {
+    use crate::CommandLimits;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn parse_profile() {
+        let limits = CommandLimits::from_toml_str(
+            "arg_size = 262144\n\
+             individual_arg_size = 131072\n\
+             arg_count = 0\n\
+             env_count = 100\n\
+             reserve = 64\n",
+        )
+        .unwrap();
+
+        assert_eq!(limits.arg_size.get(), 262144);
+        assert_eq!(limits.individual_arg_size, NonZeroUsize::new(131072));
+        assert_eq!(limits.arg_count, None);
+        assert_eq!(limits.env_size, None);
+        assert_eq!(limits.env_count, NonZeroUsize::new(100));
+        assert_eq!(limits.reserve, 64);
+    }
+
+    #[test]
+    fn parse_invalid_profile() {
+        for profile in [
+            "",
+            "arg_size = 0",
+            "arg_size = -1",
+            "arg_size = \"big\"",
+            "arg_size = 4096\nenv_count = -1",
+            "arg_size = 4096\nenv_size = 1.5",
+        ] {
+            assert!(
+                CommandLimits::from_toml_str(profile).is_err(),
+                "{:?}",
+                profile
+            );
+        }
+    }
+
+    #[test]
+    fn profile_round_trips() {
+        let limits = CommandLimits {
+            individual_arg_size: NonZeroUsize::new(131072),
+            env_count: NonZeroUsize::new(100),
+            pointer_size: crate::PointerSize::Native,
+            reserve: 64,
+            ..CommandLimits::fixed(NonZeroUsize::new(262144).unwrap())
+        };
+
+        let profile = toml::to_string(&limits).unwrap();
+        let parsed = CommandLimits::from_toml_str(&profile).unwrap();
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", limits));
+    }
+}
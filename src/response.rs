@@ -0,0 +1,53 @@
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A response file holding a command's arguments, removed when dropped.
+///
+/// Created by `CommandBuilder::into_command_with_response_file`, and must be kept
+/// until the command has finished with it.
+#[derive(Debug)]
+pub struct ResponseFile {
+    path: PathBuf,
+}
+
+impl ResponseFile {
+    /// Write `contents` to a new file in `dir`.
+    pub(crate) fn create(dir: &Path, contents: &OsString) -> io::Result<Self> {
+        loop {
+            let path = dir.join(format!(
+                "command-limits-{}-{}.rsp",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    // Removes the file again if writing fails
+                    let response = Self { path };
+                    // UTF-8 for valid Unicode, and raw bytes on Unix
+                    file.write_all(contents.as_encoded_bytes())?;
+                    return Ok(response);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Return the path of the response file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ResponseFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,50 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// A command type which a `CommandBuilder` can be assembled into.
+pub(crate) trait CommandSink {
+    fn new(program: &OsStr) -> Self;
+    fn arg(&mut self, arg: &OsStr);
+    fn env(&mut self, key: &OsStr, val: &OsStr);
+    fn env_remove(&mut self, key: &OsStr);
+    fn env_clear(&mut self);
+    fn current_dir(&mut self, dir: &Path);
+}
+
+macro_rules! impl_command_sink {
+    ($ty:ty) => {
+        impl CommandSink for $ty {
+            fn new(program: &OsStr) -> Self {
+                <$ty>::new(program)
+            }
+
+            fn arg(&mut self, arg: &OsStr) {
+                <$ty>::arg(self, arg);
+            }
+
+            fn env(&mut self, key: &OsStr, val: &OsStr) {
+                <$ty>::env(self, key, val);
+            }
+
+            fn env_remove(&mut self, key: &OsStr) {
+                <$ty>::env_remove(self, key);
+            }
+
+            fn env_clear(&mut self) {
+                <$ty>::env_clear(self);
+            }
+
+            fn current_dir(&mut self, dir: &Path) {
+                <$ty>::current_dir(self, dir);
+            }
+        }
+    };
+}
+
+impl_command_sink!(std::process::Command);
+
+#[cfg(feature = "tokio")]
+impl_command_sink!(tokio::process::Command);
+
+#[cfg(feature = "async-process")]
+impl_command_sink!(async_process::Command);
@@ -0,0 +1,69 @@
+//! The sizes `CommandBuilder` accounts for arguments and environment variables on
+//! this platform, for tools which do their own accounting.
+//!
+//! These are platform-specific.  On Unix sizes are in bytes, and include the
+//! pointer to each string assuming `PointerSize::Wide` along with its terminating
+//! NUL.  On Windows they're in UTF-16 code units, and include any quoting and
+//! escaping an argument needs on the command line.
+
+use std::ffi::OsStr;
+
+use crate::imp;
+
+/// Return the space taken by a command-line argument.
+///
+/// ```
+/// # use command_limits::sizing::arg_len;
+/// // 8 bytes for the pointer, and 1 for the NUL
+/// # #[cfg(unix)]
+/// assert_eq!(arg_len("0123456789"), 10 + 9);
+///
+/// // 2 quotes, and a space or null
+/// # #[cfg(windows)]
+/// assert_eq!(arg_len("0123456789"), 10 + 3);
+/// ```
+pub fn arg_len<S: AsRef<OsStr>>(arg: S) -> usize {
+    imp::arg_len(arg)
+}
+
+/// Return the space taken by the environment variable `key`=`value`.
+///
+/// This is always the sum of `env_key_len` and `env_val_len`.
+///
+/// ```
+/// # use command_limits::sizing::{env_key_len, env_pair_len, env_val_len};
+/// let len = env_pair_len("TZ".as_ref(), "UTC".as_ref());
+/// assert_eq!(len, env_key_len("TZ".as_ref()) + env_val_len("UTC".as_ref()));
+///
+/// // 8 bytes for the pointer, and 1 each for the = and NUL
+/// # #[cfg(unix)]
+/// assert_eq!(len, 2 + 3 + 10);
+/// ```
+pub fn env_pair_len(key: &OsStr, value: &OsStr) -> usize {
+    imp::env_pair_len(key, value)
+}
+
+/// Return the space taken by the key of an environment variable, including any
+/// pointer and the `=` separator.
+///
+/// ```
+/// # use command_limits::sizing::env_key_len;
+/// # #[cfg(unix)]
+/// assert_eq!(env_key_len("TZ".as_ref()), 2 + 9);
+/// # #[cfg(windows)]
+/// assert_eq!(env_key_len("TZ".as_ref()), 2 + 1);
+/// ```
+pub fn env_key_len(key: &OsStr) -> usize {
+    imp::env_key_len(key)
+}
+
+/// Return the space taken by the value of an environment variable, including its
+/// terminator.
+///
+/// ```
+/// # use command_limits::sizing::env_val_len;
+/// assert_eq!(env_val_len("UTC".as_ref()), 3 + 1);
+/// ```
+pub fn env_val_len(value: &OsStr) -> usize {
+    imp::env_val_len(value)
+}
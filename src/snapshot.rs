@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::{CommandBuilder, CommandLimits, EnvMap, Platform};
+
+/// The state of a `CommandBuilder` at some point, to return it to later with
+/// `restore`.
+///
+/// Unlike a clone of the builder, this doesn't hold the arguments, only how many
+/// there were, so restoring simply drops any added since.
+///
+/// Created by `CommandBuilder::snapshot`.
+#[derive(Debug, Clone)]
+pub struct BaseSnapshot {
+    limits: CommandLimits,
+    platform: Platform,
+    argv_len: usize,
+    env: Arc<EnvMap>,
+    arg_size: usize,
+    env_size: usize,
+    clear_env: bool,
+    env_frozen: bool,
+    placeholder: Option<(usize, usize)>,
+    current_dir: Option<PathBuf>,
+    wrapper_size: usize,
+}
+
+impl CommandBuilder {
+    /// Capture the current state of this command, such as a base command before
+    /// arguments are added to it for each batch.
+    pub fn snapshot(&self) -> BaseSnapshot {
+        BaseSnapshot {
+            limits: self.limits,
+            platform: self.platform,
+            argv_len: self.argv.len(),
+            env: self.env.clone(),
+            arg_size: self.arg_size,
+            env_size: self.env_size,
+            clear_env: self.clear_env,
+            env_frozen: self.env_frozen,
+            placeholder: self.placeholder,
+            current_dir: self.current_dir.clone(),
+            wrapper_size: self.wrapper_size,
+        }
+    }
+
+    /// Return this command to the state captured in `snap`, dropping any arguments
+    /// added since.
+    ///
+    /// `snap` must have been taken from this builder, or one it was cloned from,
+    /// and the arguments present then must not have been modified since, such as
+    /// with `append_to_last_arg`.  Debug builds assert there are at least as many
+    /// arguments as there were.
+    pub fn restore(&mut self, snap: &BaseSnapshot) -> &mut Self {
+        debug_assert!(
+            snap.argv_len <= self.argv.len(),
+            "restoring {} arguments from {}",
+            snap.argv_len,
+            self.argv.len()
+        );
+
+        self.limits = snap.limits;
+        self.platform = snap.platform;
+        self.argv.truncate(snap.argv_len);
+        self.env.clone_from(&snap.env);
+        self.arg_size = snap.arg_size;
+        self.env_size = snap.env_size;
+        self.clear_env = snap.clear_env;
+        self.env_frozen = snap.env_frozen;
+        self.placeholder = snap.placeholder;
+        self.current_dir.clone_from(&snap.current_dir);
+        self.wrapper_size = snap.wrapper_size;
+        if self.argv.len() < self.seen_len {
+            self.forget_seen_args();
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CommandBuilder, CommandLimits};
+    use std::ffi::OsString;
+    use std::num::NonZeroUsize;
+
+    // Everything restore should return to.
+    fn accounting(cmd: &CommandBuilder) -> (usize, usize, usize, Vec<OsString>, OsString) {
+        (
+            cmd.arg_size(),
+            cmd.env_size(),
+            cmd.remaining_arg_space(),
+            cmd.argv().map(OsString::from).collect(),
+            cmd.to_command_line(),
+        )
+    }
+
+    #[test]
+    fn restore_matches_snapshot() {
+        let limits = CommandLimits {
+            arg_count: NonZeroUsize::new(20),
+            ..CommandLimits::fixed(NonZeroUsize::new(4096).unwrap())
+        };
+        let mut cmd = CommandBuilder::empty_env_with_limits("find", limits).unwrap();
+        cmd.args(&[".", "-exec", "ls", "{}", "+"])
+            .unwrap()
+            .placeholder("{}")
+            .env("KEY", "value")
+            .unwrap();
+
+        let snap = cmd.snapshot();
+        let before = accounting(&cmd);
+
+        for _ in 0..2 {
+            cmd.args(&["a", "b", "c"])
+                .unwrap()
+                .env("KEY", "a longer value")
+                .unwrap()
+                .env("OTHER", "x")
+                .unwrap()
+                .env_remove("KEY");
+            assert_ne!(accounting(&cmd), before);
+
+            cmd.restore(&snap);
+            assert_eq!(accounting(&cmd), before);
+            assert_eq!(cmd.get_env("KEY").as_deref(), Some("value".as_ref()));
+            assert_eq!(cmd.get_env("OTHER"), None);
+        }
+
+        let mut clone = cmd.clone();
+        clone.arg("d").unwrap().restore(&snap);
+        assert_eq!(accounting(&clone), before);
+    }
+}
@@ -1,8 +1,16 @@
-use libc::{sysconf, _SC_ARG_MAX};
+use libc::{sysconf, _SC_ARG_MAX, E2BIG};
 
-use std::ffi::OsStr;
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::num::NonZeroUsize;
-use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::posix::shell_quote;
+pub use crate::posix::ArgLenBreakdown;
 
 // POSIX guarantees at least 4k of space, but wants us to reserve at least 2k
 // BSD prefers 4k, but if we were already at the floor go with POSIX
@@ -13,17 +21,37 @@ const ARG_MIN: usize = 2048;
 // _SC_ARG_MAX can be anything up to LONG_MAX, but let's not go too mad
 const ARG_MAX: usize = 2048 * 1024;
 
-// Linux limits individual argument strlen to 128k
+// Linux limits individual argument strlen to MAX_ARG_STRLEN, 32 pages
 #[cfg(target_os = "linux")]
-const ARG_SINGLE_MAX: usize = 128 * 1024;
+const ARG_SINGLE_PAGES: usize = 32;
 
-#[cfg(not(target_os = "linux"))]
-const ARG_SINGLE_MAX: usize = 0;
+#[cfg(target_os = "linux")]
+const PAGE_SIZE_DEFAULT: usize = 4096;
+
+// Linux limits the argument and environment block to a quarter of the stack
+// size limit, but never less than 128k
+#[cfg(target_os = "linux")]
+const ARG_RLIMIT_MIN: usize = 128 * 1024;
+
+// macOS copies the executable path into the argument area twice, once for
+// exec_path and again for executable_path= in the apple vector, followed by
+// other apple strings and pointer alignment.
+#[cfg(target_os = "macos")]
+const ARG_EXEC_RESERVED: usize = 2 * 1024 + 1024;
+
+// FreeBSD copies the executable path into the argument area, followed by
+// pointer alignment.
+#[cfg(target_os = "freebsd")]
+const ARG_EXEC_RESERVED: usize = 1024 + 8;
 
 // Assume 8 bytes, since 32-bit binaries may run on 64-bit operating systems,
 // and thus inherit those limits.
 const MAX_POINTER_SIZE: usize = 8;
 
+// The argv and envp arrays each end with a null pointer, which aren't counted
+// with any argument or variable, so are reserved from the total.
+const ARG_TERMINATORS: usize = 2 * MAX_POINTER_SIZE;
+
 fn _sc_arg_max() -> Option<usize> {
     let arg_max = unsafe { sysconf(_SC_ARG_MAX) };
 
@@ -34,13 +62,138 @@ fn _sc_arg_max() -> Option<usize> {
     }
 }
 
+// Read kern.argmax directly and apply the known reservations in place of the
+// generic POSIX reserve.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn sysctl_arg_max() -> Option<usize> {
+    let mut arg_max: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>();
+
+    let res = unsafe {
+        libc::sysctlbyname(
+            b"kern.argmax\0".as_ptr().cast(),
+            (&mut arg_max as *mut libc::c_int).cast(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+
+    if res == 0 && arg_max > 0 {
+        Some((arg_max as usize).saturating_sub(ARG_EXEC_RESERVED))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+fn sysctl_arg_max() -> Option<usize> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn arg_single_max() -> Option<NonZeroUsize> {
+    let page_size = unsafe { sysconf(libc::_SC_PAGESIZE) };
+
+    if page_size > 0 {
+        NonZeroUsize::new(ARG_SINGLE_PAGES * page_size as usize)
+    } else {
+        NonZeroUsize::new(ARG_SINGLE_PAGES * PAGE_SIZE_DEFAULT)
+    }
+}
+
+// Other systems have no limit on individual strings beyond the total.
+#[cfg(not(target_os = "linux"))]
+fn arg_single_max() -> Option<NonZeroUsize> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn rlimit_arg_max() -> Option<usize> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_STACK, &mut rlim) } != 0
+        || rlim.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+
+    stack_arg_max(rlim.rlim_cur)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rlimit_arg_max() -> Option<usize> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn stack_arg_max(stack_limit: libc::rlim_t) -> Option<usize> {
+    usize::try_from(stack_limit / 4)
+        .ok()
+        .map(|limit| limit.max(ARG_RLIMIT_MIN))
+}
+
+// The kernel's own view of the stack limit, in case getrlimit disagrees, such as
+// under some container runtimes.
+#[cfg(target_os = "linux")]
+fn proc_rlimit_arg_max() -> Option<usize> {
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    parse_proc_stack_limit(&limits).and_then(stack_arg_max)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn proc_rlimit_arg_max() -> Option<usize> {
+    None
+}
+
+// Return the soft stack limit from the contents of /proc/self/limits, if it isn't
+// unlimited:
+//
+// Limit                     Soft Limit           Hard Limit           Units
+// Max stack size            8388608              unlimited            bytes
+#[cfg(target_os = "linux")]
+fn parse_proc_stack_limit(limits: &str) -> Option<libc::rlim_t> {
+    limits
+        .lines()
+        .find_map(|line| line.strip_prefix("Max stack size"))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|soft| soft.parse().ok())
+}
+
 pub(crate) fn osstr_len<S: AsRef<OsStr>>(s: S) -> usize {
     s.as_ref().as_bytes().len()
 }
 
+pub(crate) fn bytes_to_os(bytes: &[u8]) -> &OsStr {
+    OsStr::from_bytes(bytes)
+}
+
+pub(crate) fn replace_osstr(s: &OsStr, from: &OsStr, to: &OsStr) -> OsString {
+    OsString::from_vec(crate::replace_units(
+        s.as_bytes(),
+        from.as_bytes(),
+        to.as_bytes(),
+    ))
+}
+
 pub(crate) fn arg_len<S: AsRef<OsStr>>(arg: S) -> usize {
+    arg_len_for(osstr_len(arg))
+}
+
+pub(crate) fn arg_len_for(len: usize) -> usize {
     // char * {arg}\0
-    MAX_POINTER_SIZE + osstr_len(arg) + 1
+    MAX_POINTER_SIZE + len + 1
+}
+
+pub(crate) fn arg_len_breakdown<S: AsRef<OsStr>>(arg: S) -> ArgLenBreakdown {
+    ArgLenBreakdown {
+        pointer_bytes: MAX_POINTER_SIZE,
+        content_bytes: osstr_len(arg),
+        terminator_bytes: 1,
+    }
 }
 
 pub(crate) fn env_pair_len(k: &OsStr, v: &OsStr) -> usize {
@@ -57,21 +210,145 @@ pub(crate) fn env_val_len(v: &OsStr) -> usize {
     osstr_len(v) + 1
 }
 
+pub(crate) fn env_pair_len_for(key_len: usize, val_len: usize) -> usize {
+    // char * {k}={v}\0
+    MAX_POINTER_SIZE + key_len + 1 + val_len + 1
+}
+
+// Environment variable names are case sensitive.
+pub(crate) fn cmp_env_keys(a: &OsStr, b: &OsStr) -> Ordering {
+    a.cmp(b)
+}
+
+pub(crate) fn hash_env_key<H: Hasher>(key: &OsStr, state: &mut H) {
+    key.hash(state);
+}
+
+// Arguments and environment variables are passed as C strings, so anything with
+// an interior NUL cannot be represented.
+pub(crate) fn is_valid_encoding(s: &OsStr) -> bool {
+    !s.as_bytes().contains(&0)
+}
+
+// A PATH entry only matches if it names a file with an execute bit set.
+pub(crate) fn executable_path(path: &Path) -> Option<PathBuf> {
+    path.metadata()
+        .ok()
+        .filter(|meta| meta.permissions().mode() & 0o111 != 0)
+        .map(|_| path.to_owned())
+}
+
+// Append `arg` to `line` quoted for a POSIX shell.
+pub(crate) fn push_quoted_arg(line: &mut OsString, arg: &OsStr) {
+    line.push(OsStr::from_bytes(&shell_quote(arg.as_bytes())));
+}
+
+pub(crate) fn is_arg_list_too_long(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(E2BIG)
+}
+
+// Limits derived from the system, with `rlimit_arg_max` as the limit implied by
+// the stack size.
+fn limits_with(rlimit_arg_max: Option<usize>) -> crate::CommandLimits {
+    let arg_max = sysctl_arg_max()
+        .map(|arg_max| arg_max.min(ARG_MAX))
+        .unwrap_or_else(|| {
+            ARG_MAX
+                .min(_sc_arg_max().unwrap_or_default())
+                .min(rlimit_arg_max.unwrap_or(usize::MAX))
+                .max(ARG_POSIX_MIN)
+                .saturating_sub(ARG_RESERVED)
+        })
+        .saturating_sub(ARG_TERMINATORS)
+        .max(ARG_MIN);
+    let arg_single_max = arg_single_max();
+
+    crate::CommandLimits {
+        arg_size: NonZeroUsize::new(arg_max).unwrap(),
+        individual_arg_size: arg_single_max,
+        arg_count: None,
+        env_size: None,
+        individual_env_size: arg_single_max,
+        env_count: None,
+        total_size: None,
+        pointer_size: Default::default(),
+        reserve: 0,
+    }
+}
+
 impl Default for crate::CommandLimits {
     fn default() -> Self {
-        let arg_max = ARG_MAX
-            .min(_sc_arg_max().unwrap_or_default())
-            .max(ARG_POSIX_MIN)
-            .saturating_sub(ARG_RESERVED)
-            .max(ARG_MIN);
-
-        Self {
-            arg_size: NonZeroUsize::new(arg_max).unwrap(),
-            individual_arg_size: NonZeroUsize::new(ARG_SINGLE_MAX),
-            arg_count: None,
-            env_size: None,
-            individual_env_size: NonZeroUsize::new(ARG_SINGLE_MAX),
-            env_count: None,
-        }
+        limits_with(rlimit_arg_max())
+    }
+}
+
+// As the default, but also checking the stack limit reported by /proc on Linux,
+// and taking the smaller.
+pub(crate) fn detect_limits() -> crate::CommandLimits {
+    let rlimit_arg_max = match (rlimit_arg_max(), proc_rlimit_arg_max()) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+
+    limits_with(rlimit_arg_max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    const LIMITS: &str = "\
+Limit                     Soft Limit           Hard Limit           Units
+Max cpu time              unlimited            unlimited            seconds
+Max file size             unlimited            unlimited            bytes
+Max stack size            8388608              unlimited            bytes
+Max core file size        0                    unlimited            bytes
+";
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_numeric_stack_limit() {
+        assert_eq!(parse_proc_stack_limit(LIMITS), Some(8388608));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_unlimited_stack_limit() {
+        let limits = LIMITS.replace("8388608", "unlimited");
+        assert_eq!(parse_proc_stack_limit(&limits), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_missing_stack_limit() {
+        let limits: String = LIMITS
+            .lines()
+            .filter(|line| !line.starts_with("Max stack size"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert_eq!(parse_proc_stack_limit(&limits), None);
+        assert_eq!(parse_proc_stack_limit(""), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn individual_arg_size_on_linux() {
+        let page_size = unsafe { sysconf(libc::_SC_PAGESIZE) } as usize;
+        let expected = NonZeroUsize::new(ARG_SINGLE_PAGES * page_size);
+
+        let limits = crate::CommandLimits::default();
+        assert_eq!(limits.individual_arg_size, expected);
+        assert_eq!(limits.individual_env_size, expected);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[test]
+    fn no_individual_arg_size_elsewhere() {
+        assert_eq!(arg_single_max(), None);
+
+        let limits = crate::CommandLimits::default();
+        assert_eq!(limits.individual_arg_size, None);
+        assert_eq!(limits.individual_env_size, None);
     }
 }
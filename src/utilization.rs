@@ -0,0 +1,39 @@
+use std::fmt;
+
+/// A snapshot of how much of its limits a command is using.
+///
+/// Created by `CommandBuilder::utilization`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Utilization {
+    /// Space used by the program and arguments.
+    pub arg_bytes_used: usize,
+    /// Space usable for arguments, including the environment if `shared_space`.
+    pub arg_bytes_total: usize,
+    /// Space used by the environment.
+    pub env_bytes_used: usize,
+    /// Space usable for the environment, which is `arg_bytes_total` if
+    /// `shared_space`.
+    pub env_bytes_total: usize,
+    /// The number of arguments, including the program.
+    pub arg_count_used: usize,
+    /// The limit on the number of arguments, if any.
+    pub arg_count_limit: Option<usize>,
+    /// Whether arguments and environment share `arg_bytes_total`.
+    pub shared_space: bool,
+}
+
+impl fmt::Display for Utilization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Available argument space: {}", self.arg_bytes_total)?;
+        if !self.shared_space {
+            writeln!(f, "Available environment space: {}", self.env_bytes_total)?;
+        }
+        writeln!(f, "Space used by arguments: {}", self.arg_bytes_used)?;
+        writeln!(f, "Space used by environment: {}", self.env_bytes_used)?;
+        write!(f, "Number of arguments: {}", self.arg_count_used)?;
+        if let Some(limit) = self.arg_count_limit {
+            write!(f, " of {}", limit)?;
+        }
+        Ok(())
+    }
+}
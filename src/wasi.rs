@@ -0,0 +1,122 @@
+// WASI passes arguments and environment to a module as with `args_get` and
+// `environ_get`: a contiguous buffer of NUL-terminated strings, plus an array of
+// pointers into it, both in the module's own memory.
+
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::num::NonZeroUsize;
+use std::os::wasi::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use crate::posix::shell_quote;
+pub use crate::posix::ArgLenBreakdown;
+
+// WASI sets no limit of its own, but a runtime is given its arguments by the host,
+// so assume the smallest limit of a common host: 256k on older macOS.
+const ARG_RESERVED: usize = 4096;
+const ARG_MAX: usize = 256 * 1024 - ARG_RESERVED;
+
+// Pointers are 4 bytes on wasm32 but 8 on wasm64, so assume the wider as for Unix.
+const MAX_POINTER_SIZE: usize = 8;
+
+// wasi-libc ends the argv and environ arrays with a null pointer, as on Unix.
+const ARG_TERMINATORS: usize = 2 * MAX_POINTER_SIZE;
+
+pub(crate) fn osstr_len<S: AsRef<OsStr>>(s: S) -> usize {
+    s.as_ref().as_bytes().len()
+}
+
+pub(crate) fn bytes_to_os(bytes: &[u8]) -> &OsStr {
+    OsStr::from_bytes(bytes)
+}
+
+pub(crate) fn replace_osstr(s: &OsStr, from: &OsStr, to: &OsStr) -> OsString {
+    OsString::from_vec(crate::replace_units(
+        s.as_bytes(),
+        from.as_bytes(),
+        to.as_bytes(),
+    ))
+}
+
+pub(crate) fn arg_len<S: AsRef<OsStr>>(arg: S) -> usize {
+    arg_len_for(osstr_len(arg))
+}
+
+pub(crate) fn arg_len_for(len: usize) -> usize {
+    // char * {arg}\0
+    MAX_POINTER_SIZE + len + 1
+}
+
+pub(crate) fn arg_len_breakdown<S: AsRef<OsStr>>(arg: S) -> ArgLenBreakdown {
+    ArgLenBreakdown {
+        pointer_bytes: MAX_POINTER_SIZE,
+        content_bytes: osstr_len(arg),
+        terminator_bytes: 1,
+    }
+}
+
+pub(crate) fn env_pair_len(k: &OsStr, v: &OsStr) -> usize {
+    env_key_len(k) + env_val_len(v)
+}
+
+pub(crate) fn env_key_len(k: &OsStr) -> usize {
+    // char * {k}=
+    MAX_POINTER_SIZE + osstr_len(k) + 1
+}
+
+pub(crate) fn env_val_len(v: &OsStr) -> usize {
+    // {v}\0
+    osstr_len(v) + 1
+}
+
+pub(crate) fn env_pair_len_for(key_len: usize, val_len: usize) -> usize {
+    // char * {k}={v}\0
+    MAX_POINTER_SIZE + key_len + 1 + val_len + 1
+}
+
+// Environment variable names are case sensitive.
+pub(crate) fn cmp_env_keys(a: &OsStr, b: &OsStr) -> Ordering {
+    a.cmp(b)
+}
+
+pub(crate) fn hash_env_key<H: Hasher>(key: &OsStr, state: &mut H) {
+    key.hash(state);
+}
+
+// Strings are NUL-terminated in the buffer, so cannot contain NUL themselves.
+pub(crate) fn is_valid_encoding(s: &OsStr) -> bool {
+    !s.as_bytes().contains(&0)
+}
+
+// There are no execute permissions, so accept any file.
+pub(crate) fn executable_path(path: &Path) -> Option<PathBuf> {
+    path.is_file().then(|| path.to_owned())
+}
+
+// Spawning isn't supported, so there's no error for an over-long command.
+pub(crate) fn is_arg_list_too_long(_e: &io::Error) -> bool {
+    false
+}
+
+// Quote as for a POSIX shell, as a runtime is usually started from one.
+pub(crate) fn push_quoted_arg(line: &mut OsString, arg: &OsStr) {
+    line.push(OsStr::from_bytes(&shell_quote(arg.as_bytes())));
+}
+
+impl Default for crate::CommandLimits {
+    fn default() -> Self {
+        Self {
+            arg_size: NonZeroUsize::new(ARG_MAX - ARG_TERMINATORS).unwrap(),
+            individual_arg_size: None,
+            arg_count: None,
+            env_size: None,
+            individual_env_size: None,
+            env_count: None,
+            total_size: None,
+            pointer_size: Default::default(),
+            reserve: 0,
+        }
+    }
+}
@@ -1,6 +1,10 @@
-use std::ffi::OsStr;
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::hash::Hasher;
+use std::io;
 use std::num::NonZeroUsize;
-use std::os::windows::ffi::OsStrExt;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
 
 // Reserve a little, just in case.
 const ARG_RESERVED: usize = 4096;
@@ -9,31 +13,71 @@ const ARG_RESERVED: usize = 4096;
 // they both share the same limit
 const ARG_MAX: usize = 32767 - ARG_RESERVED;
 
+// The environment block ends with an extra null, which isn't counted with any
+// variable, so is reserved from its total.
+const ENV_TERMINATOR: usize = 1;
+
 pub(crate) fn osstr_len<S: AsRef<OsStr>>(s: S) -> usize {
     s.as_ref().encode_wide().count()
 }
 
+pub(crate) fn bytes_to_os(bytes: &[u8]) -> OsString {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+pub(crate) fn replace_osstr(s: &OsStr, from: &OsStr, to: &OsStr) -> OsString {
+    let s: Vec<u16> = s.encode_wide().collect();
+    let from: Vec<u16> = from.encode_wide().collect();
+    let to: Vec<u16> = to.encode_wide().collect();
+    OsString::from_wide(&crate::replace_units(&s, &from, &to))
+}
+
 // Command line arguments are passed as a single contiguous string with elements
 // quoted and escaped.
 //
-// Estimate how big the resulting string will be by double-counting backslashes
-// and quotes, and assume quotes either side followed by a space or null
+// Count the backslashes needed to escape quotes and any backslashes preceding
+// them, and assume quotes either side followed by a space or null.  Quotes are
+// always counted, since they may be forced even when not otherwise needed, and
+// are required for an empty argument.
 pub(crate) fn arg_len<S: AsRef<OsStr>>(arg: S) -> usize {
-    arg.as_ref()
-        .encode_wide()
-        .map(|ch| {
-            if ch == b'\\' as u16 || ch == b'"' as u16 {
-                2
-            } else {
-                1
-            }
-        })
-        .sum::<usize>()
-        + 3
+    arg_len_breakdown(arg).total()
+}
+
+/// How the space taken by an argument is made up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ArgLenBreakdown {
+    /// The UTF-16 code units of the argument itself.
+    pub content_units: usize,
+    /// The surrounding quotes and the following space or null.
+    pub quote_units: usize,
+    /// Backslashes added to escape quotes, and backslashes preceding a quote.
+    pub escape_units: usize,
+}
+
+impl ArgLenBreakdown {
+    /// Return the total, as used for accounting.
+    pub fn total(&self) -> usize {
+        self.content_units + self.quote_units + self.escape_units
+    }
+}
+
+pub(crate) fn arg_len_breakdown<S: AsRef<OsStr>>(arg: S) -> ArgLenBreakdown {
+    let (content_units, escape_units) = crate::windows_arg_units(arg.as_ref().encode_wide());
+
+    ArgLenBreakdown {
+        content_units,
+        quote_units: 3,
+        escape_units,
+    }
+}
+
+// Assumes nothing needs escaping.
+pub(crate) fn arg_len_for(len: usize) -> usize {
+    len + 3
 }
 
 // Windows stores the environment as a null-delimited list of strings, which is
-// itself null delimited.  We don't include the ending null for simplicity.
+// itself null delimited.  The ending null is reserved from env_size instead.
 pub(crate) fn env_pair_len(k: &OsStr, v: &OsStr) -> usize {
     env_key_len(k) + env_val_len(v)
 }
@@ -46,15 +90,174 @@ pub(crate) fn env_val_len(k: &OsStr) -> usize {
     osstr_len(k) + 1
 }
 
+pub(crate) fn env_pair_len_for(key_len: usize, val_len: usize) -> usize {
+    key_len + 1 + val_len + 1
+}
+
+// Environment variable names are case insensitive, so compare them uppercased.
+// Characters without a single uppercase equivalent, and unpaired surrogates, are
+// left alone.
+fn fold_env_key(key: &OsStr) -> impl Iterator<Item = u16> + '_ {
+    char::decode_utf16(key.encode_wide()).flat_map(|c| {
+        let mut units = [0; 2];
+        let len = match c {
+            Ok(c) => {
+                let mut upper = c.to_uppercase();
+                let c = match (upper.next(), upper.next()) {
+                    (Some(u), None) => u,
+                    _ => c,
+                };
+                c.encode_utf16(&mut units).len()
+            }
+            Err(e) => {
+                units[0] = e.unpaired_surrogate();
+                1
+            }
+        };
+        units.into_iter().take(len)
+    })
+}
+
+pub(crate) fn cmp_env_keys(a: &OsStr, b: &OsStr) -> Ordering {
+    fold_env_key(a).cmp(fold_env_key(b))
+}
+
+pub(crate) fn hash_env_key<H: Hasher>(key: &OsStr, state: &mut H) {
+    let mut len = 0;
+    for unit in fold_env_key(key) {
+        state.write_u16(unit);
+        len += 1;
+    }
+    // As with slices, so that consecutive keys can't collide by moving units
+    state.write_usize(len);
+}
+
+// Strings are passed as null-terminated UTF-16, so reject interior NULs and
+// unpaired surrogates that cannot be represented losslessly.
+pub(crate) fn is_valid_encoding(s: &OsStr) -> bool {
+    std::char::decode_utf16(s.encode_wide()).all(|ch| matches!(ch, Ok(ch) if ch != '\0'))
+}
+
+// CreateProcess appends .exe to programs without an extension.
+pub(crate) fn executable_path(path: &Path) -> Option<PathBuf> {
+    if path.extension().is_none() {
+        Some(path.with_extension("exe"))
+    } else {
+        Some(path.to_owned())
+    }
+}
+
+// Append `arg` to `line` quoted as CommandLineToArgvW expects, as described for
+// arg_len.  Quotes are only added where needed.
+pub(crate) fn push_quoted_arg(line: &mut OsString, arg: &OsStr) {
+    let units: Vec<u16> = arg.encode_wide().collect();
+    let quote = units.is_empty()
+        || units
+            .iter()
+            .any(|&ch| ch == b' ' as u16 || ch == b'\t' as u16 || ch == b'"' as u16);
+
+    if !quote {
+        line.push(arg);
+        return;
+    }
+
+    let mut quoted = Vec::with_capacity(units.len() + 2);
+    let mut backslashes = 0;
+    quoted.push(b'"' as u16);
+    for ch in units {
+        if ch == b'\\' as u16 {
+            backslashes += 1;
+        } else {
+            if ch == b'"' as u16 {
+                quoted.extend(std::iter::repeat_n(b'\\' as u16, backslashes + 1));
+            }
+            backslashes = 0;
+        }
+        quoted.push(ch);
+    }
+    quoted.extend(std::iter::repeat_n(b'\\' as u16, backslashes));
+    quoted.push(b'"' as u16);
+    line.push(OsString::from_wide(&quoted));
+}
+
+// CreateProcess fails with ERROR_FILENAME_EXCED_RANGE if the command line is
+// too long.
+pub(crate) fn is_arg_list_too_long(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(206)
+}
+
 impl Default for crate::CommandLimits {
     fn default() -> Self {
         Self {
             arg_size: NonZeroUsize::new(ARG_MAX).unwrap(),
             individual_arg_size: None,
             arg_count: None,
-            env_size: NonZeroUsize::new(ARG_MAX),
+            env_size: NonZeroUsize::new(ARG_MAX - ENV_TERMINATOR),
             individual_env_size: None,
             env_count: None,
+            total_size: None,
+            pointer_size: Default::default(),
+            reserve: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[link(name = "shell32")]
+    extern "system" {
+        fn CommandLineToArgvW(line: *const u16, argc: *mut i32) -> *mut *mut u16;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LocalFree(mem: *mut std::ffi::c_void) -> *mut std::ffi::c_void;
+    }
+
+    fn command_line_to_argv(line: &OsStr) -> Vec<OsString> {
+        let line: Vec<u16> = line.encode_wide().chain([0]).collect();
+        let mut argc = 0;
+        unsafe {
+            let argv = CommandLineToArgvW(line.as_ptr(), &mut argc);
+            assert!(!argv.is_null());
+            let args = (0..argc as usize)
+                .map(|i| {
+                    let arg = *argv.add(i);
+                    let len = (0..).take_while(|&j| *arg.add(j) != 0).count();
+                    OsString::from_wide(std::slice::from_raw_parts(arg, len))
+                })
+                .collect();
+            LocalFree(argv.cast());
+            args
+        }
+    }
+
+    #[test]
+    fn quoted_args_round_trip() {
+        let args = [
+            "plain",
+            "",
+            "with space",
+            "tab\there",
+            "\"quoted\"",
+            r"trailing\",
+            r"trailing\\",
+            r#"back\"slash"#,
+            r#"back\\"slashes"#,
+            r"C:\Program Files\",
+            "unicode \u{e9}\u{1f600}",
+        ];
+
+        let mut line = OsString::from("program");
+        for arg in args {
+            line.push(" ");
+            push_quoted_arg(&mut line, OsStr::new(arg));
+        }
+
+        let parsed = command_line_to_argv(&line);
+        assert_eq!(parsed[0], "program");
+        assert!(parsed[1..].iter().eq(args), "{:?}", line);
+    }
+}
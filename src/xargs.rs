@@ -0,0 +1,100 @@
+use std::cell::Cell;
+use std::io::{self, BufRead, Write};
+
+use crate::imp::bytes_to_os;
+use crate::parse::{read_null_delimited, read_shell_word};
+use crate::{pack, BatchOutcome, CommandBuilder, ExitPolicy};
+
+/// Options for `run_xargs`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct XargsOpts {
+    /// Read NUL-delimited items, as with `xargs -0`, rather than shell words.
+    pub null: bool,
+    /// How the exit statuses of the commands are combined into the one returned.
+    pub exit_policy: ExitPolicy,
+}
+
+/// Run `base` with items read from `input` as its arguments, as many at a time as
+/// will fit, in the manner of `xargs`.
+///
+/// Each command line is written to `trace` before it's run, as with `xargs -t`;
+/// pass `io::sink()` to discard them.  Each command is run to completion before
+/// the next.  If one exits with status 255 or is killed by a signal no more are
+/// run, as with POSIX `xargs`, and `BatchOutcome::should_stop` is true.  Use
+/// `BatchOutcome::exit_code` for the statuses combined according to
+/// `opts.exit_policy`.
+///
+/// Empty items are skipped when reading shell words, but passed on as empty
+/// arguments with `opts.null`.
+///
+/// An item which cannot be added even to an otherwise empty command, such as one
+/// too large or containing a NUL, is returned as an `InvalidInput` error after
+/// running the commands before it.
+pub fn run_xargs<R: BufRead, W: Write>(
+    base: CommandBuilder,
+    mut input: R,
+    mut trace: W,
+    opts: XargsOpts,
+) -> io::Result<BatchOutcome> {
+    // A read error ends the items, and is returned in place of the batch it cut
+    // short.
+    let error = Cell::new(None);
+    let items = std::iter::from_fn(|| {
+        let item = if opts.null {
+            read_null_delimited(&mut input)
+        } else {
+            read_shell_word(&mut input)
+        };
+        match item? {
+            Ok(item) => Some(item),
+            Err(e) => {
+                error.set(Some(e));
+                None
+            }
+        }
+    })
+    .filter(|item| opts.null || !item.is_empty())
+    .map(|item| bytes_to_os(&item).to_owned());
+
+    let mut outcome = BatchOutcome::new(opts.exit_policy);
+
+    for cmd in pack(base, items) {
+        if let Some(e) = error.take() {
+            return Err(e);
+        }
+        let cmd = cmd.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        trace.write_all(cmd.to_command_line().as_encoded_bytes())?;
+        trace.write_all(b"\n")?;
+        outcome.push(cmd.into_command().status()?);
+        if outcome.should_stop() {
+            return Ok(outcome);
+        }
+    }
+
+    if let Some(e) = error.take() {
+        return Err(e);
+    }
+
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::CommandLimits;
+
+    #[test]
+    fn item_not_fitting_empty_command_is_an_error() {
+        let limits = CommandLimits {
+            arg_count: NonZeroUsize::new(1),
+            ..CommandLimits::fixed(NonZeroUsize::new(100_000).unwrap())
+        };
+        let base = CommandBuilder::empty_env_with_limits("true", limits).unwrap();
+
+        let err = run_xargs(base, &b"a b c"[..], io::sink(), Default::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,67 @@
+//! Spawn the commands converted for async runtimes, checking the arguments and
+//! environment reach the program.
+#![cfg(all(unix, any(feature = "tokio", feature = "async-process")))]
+
+use command_limits::CommandBuilder;
+
+// A command which exits 0 only if its argument and environment arrived intact.
+fn check(arg: &str, value: &str) -> CommandBuilder {
+    let mut cmd = CommandBuilder::new("sh").unwrap();
+    cmd.args(&["-c", r#"test "$1" = a && test "$KEY" = value"#, "sh", arg])
+        .unwrap()
+        .env("KEY", value)
+        .unwrap();
+    cmd
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn tokio_command_runs() {
+    let status = check("a", "value")
+        .into_tokio_command()
+        .status()
+        .await
+        .unwrap();
+    assert!(status.success());
+
+    let status = check("b", "value")
+        .into_tokio_command()
+        .status()
+        .await
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+
+    let status = check("a", "other")
+        .into_tokio_command()
+        .status()
+        .await
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+}
+
+#[cfg(feature = "async-process")]
+#[test]
+fn async_process_command_runs() {
+    futures_lite::future::block_on(async {
+        let status = check("a", "value")
+            .into_async_command()
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+
+        let status = check("b", "value")
+            .into_async_command()
+            .status()
+            .await
+            .unwrap();
+        assert_eq!(status.code(), Some(1));
+
+        let status = check("a", "other")
+            .into_async_command()
+            .status()
+            .await
+            .unwrap();
+        assert_eq!(status.code(), Some(1));
+    });
+}
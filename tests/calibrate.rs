@@ -0,0 +1,106 @@
+//! Compare the default limits with what the system actually accepts.
+//!
+//! Binary searches for the largest argument list which can be passed to
+//! `/bin/true` with an empty environment, and the largest which `CommandBuilder`
+//! accepts with `CommandLimits::default()`, and fails if the latter is larger.  This
+//! spawns many processes, so needs the `calibrate` feature and is ignored unless
+//! requested:
+//!
+//!     cargo test --features calibrate --test calibrate -- --ignored --nocapture
+#![cfg(all(unix, feature = "calibrate"))]
+
+use command_limits::{CommandBuilder, CommandLimits};
+
+use std::ffi::OsString;
+use std::io;
+use std::num::NonZeroUsize;
+use std::process::Command;
+
+const PROGRAM: &str = "/bin/true";
+
+// Split the payload into arguments well below any individual argument limit.
+const CHUNK: usize = 4096;
+
+// Give up looking for a real limit beyond this.
+const SEARCH_MAX: usize = 1 << 30;
+
+fn payload(len: usize) -> Vec<OsString> {
+    let mut args = vec![OsString::from("x".repeat(CHUNK)); len / CHUNK];
+    if !len.is_multiple_of(CHUNK) {
+        args.push("x".repeat(len % CHUNK).into());
+    }
+    args
+}
+
+fn spawns(len: usize) -> io::Result<bool> {
+    match Command::new(PROGRAM)
+        .env_clear()
+        .args(payload(len))
+        .status()
+    {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::ArgumentListTooLong => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn builder(limits: CommandLimits) -> CommandBuilder {
+    CommandBuilder::empty_env_with_limits(PROGRAM, limits).expect("program should fit")
+}
+
+fn accepts(len: usize) -> bool {
+    builder(CommandLimits::default())
+        .args(&payload(len))
+        .is_ok()
+}
+
+// Return the largest length up to `max` for which `test` holds, given that it holds
+// for 0 and is monotonic.
+fn largest<F>(max: usize, mut test: F) -> io::Result<usize>
+where
+    F: FnMut(usize) -> io::Result<bool>,
+{
+    let (mut lo, mut hi) = (0, max);
+    while lo < hi {
+        let mid = lo + (hi - lo).div_ceil(2);
+        if test(mid)? {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    Ok(lo)
+}
+
+#[test]
+#[ignore]
+fn default_limits_are_conservative() -> io::Result<()> {
+    let mut max = CHUNK;
+    while max < SEARCH_MAX && spawns(max)? {
+        max *= 2;
+    }
+
+    let real = largest(max, spawns)?;
+    let accepted = largest(max, |len| Ok(accepts(len)))?;
+
+    let mut cmd = builder(CommandLimits::fixed(NonZeroUsize::MAX));
+    cmd.args(&payload(real))
+        .expect("unlimited builder should accept");
+
+    println!("Largest payload spawned:  {}", real);
+    println!("Largest payload accepted: {}", accepted);
+    println!("Estimate at real limit:   {}", cmd.estimated_total_bytes());
+    println!(
+        "Default arg_size:         {}",
+        CommandLimits::default().arg_size
+    );
+
+    assert!(real < max, "no limit found below {} bytes", max);
+    assert!(
+        accepted <= real,
+        "default limits accept {} bytes, but the system rejects more than {}",
+        accepted,
+        real
+    );
+    Ok(())
+}
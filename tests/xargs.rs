@@ -0,0 +1,110 @@
+#![cfg(unix)]
+
+use command_limits::{run_xargs, CommandBuilder, XargsOpts};
+
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+// A file for the commands to log their arguments to, removed when dropped.
+struct Log(PathBuf);
+
+impl Log {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "command-limits-{}-{}.log",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        Self(path)
+    }
+
+    fn lines(&self) -> Vec<String> {
+        fs::read_to_string(&self.0)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+}
+
+impl Drop for Log {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// A command which appends a line to `log` with its arguments in brackets each
+// time it's run.
+fn logger(log: &Log) -> CommandBuilder {
+    let mut cmd = CommandBuilder::new_empty_env("/bin/sh").unwrap();
+    cmd.arg("-c")
+        .unwrap()
+        .arg(r#"log=$1; shift; printf '[%s]' "$@" >> "$log"; echo >> "$log""#)
+        .unwrap()
+        .arg("sh")
+        .unwrap()
+        .arg(&log.0)
+        .unwrap();
+    cmd
+}
+
+#[test]
+fn invokes_once_per_batch() {
+    let log = Log::new("invokes_once_per_batch");
+    let mut base = logger(&log);
+    base.set_max_data_args(NonZeroUsize::new(3)).unwrap();
+
+    let mut trace = vec![];
+    let input = "a b\nc 'd e' f\n  g\n";
+    let outcome = run_xargs(base, input.as_bytes(), &mut trace, Default::default()).unwrap();
+
+    assert_eq!(log.lines(), ["[a][b][c]", "[d e][f][g]"]);
+    assert_eq!(outcome.statuses().len(), 2);
+    assert_eq!(outcome.exit_code(), 0);
+    assert_eq!(String::from_utf8(trace).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn null_delimited_keeps_empty_items() {
+    let log = Log::new("null_delimited_keeps_empty_items");
+    let opts = XargsOpts {
+        null: true,
+        ..Default::default()
+    };
+
+    let outcome = run_xargs(logger(&log), &b"a\0\0b c\0"[..], io::sink(), opts).unwrap();
+
+    assert_eq!(log.lines(), ["[a][][b c]"]);
+    assert_eq!(outcome.statuses().len(), 1);
+}
+
+#[test]
+fn whitespace_delimited_skips_empty_items() {
+    let log = Log::new("whitespace_delimited_skips_empty_items");
+
+    run_xargs(
+        logger(&log),
+        &b"a  \n\n b"[..],
+        io::sink(),
+        Default::default(),
+    )
+    .unwrap();
+
+    assert_eq!(log.lines(), ["[a][b]"]);
+}
+
+#[test]
+fn stops_after_status_255() {
+    let mut base = CommandBuilder::new_empty_env("/bin/sh").unwrap();
+    base.args(&["-c", "exit 255", "sh"]).unwrap();
+    base.set_max_data_args(NonZeroUsize::new(1)).unwrap();
+
+    let outcome = run_xargs(base, &b"a b c"[..], io::sink(), Default::default()).unwrap();
+
+    assert!(outcome.should_stop());
+    assert_eq!(outcome.statuses().len(), 1);
+    assert_eq!(outcome.exit_code(), 124);
+}